@@ -0,0 +1,13 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use speed_daemon::protocol::ServerCodec;
+use tokio_util::codec::Decoder;
+
+// Decodes a stream of requests as the server would, which must never panic whatever the client sends.
+fuzz_target!(|data: &[u8]| {
+    let mut bytes = BytesMut::from(data);
+    while let Ok(Some(_)) = ServerCodec.decode(&mut bytes) {}
+    let _ = ServerCodec.decode_eof(&mut bytes);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use speed_daemon::protocol::deserialize_request;
+use speed_daemon::protocol::serialize_request;
+
+// Any request that parses must serialize back to the bytes it was parsed from.
+fuzz_target!(|data: &[u8]| {
+    let mut iter = data.iter().copied();
+    if let Ok(request) = deserialize_request(&mut iter) {
+        let consumed = data.len() - iter.len();
+        assert_eq!(serialize_request(&request).unwrap(), &data[..consumed]);
+    }
+});
@@ -0,0 +1,318 @@
+use crate::protocol::IAmCamera;
+use crate::protocol::Ticket;
+use crate::roads::Road;
+use crate::server::State;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::task::spawn;
+use tokio::time::sleep;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// Serves each admin HTTP request on its own task. After failing to accept a connection, as when out of file
+/// descriptors, it waits for [`ACCEPT_BACKOFF`] before trying again.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<State>) -> io::Result<()> {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!(%err, "failed to accept an admin connection");
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        spawn(async move { handle_request(stream, &state).await });
+    }
+}
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Answers a single request and closes the connection. Only `GET /state` and `GET /metrics` are supported.
+/// Clients that do not send their request within [`REQUEST_TIMEOUT`] are hung up on.
+async fn handle_request(mut stream: TcpStream, state: &State) -> io::Result<()> {
+    let request_line = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request_line) => request_line?,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "admin request timed out",
+        ))?,
+    };
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/state")) => {
+            let snapshot = Snapshot::take(state).await;
+            let body = serde_json::to_string(&snapshot)?;
+            http_response("200 OK", "application/json", &body)
+        }
+        (Some("GET"), Some("/metrics")) => {
+            let pending_tickets = state
+                .dispatchers
+                .lock()
+                .await
+                .pending()
+                .values()
+                .map(|tickets| tickets.len())
+                .sum();
+            let body = state.metrics.render(pending_tickets);
+            http_response("200 OK", "text/plain; version=0.0.4", &body)
+        }
+        (Some("GET"), Some(_)) => http_response("404 Not Found", "text/plain", "not found\n"),
+        _ => http_response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n",
+        ),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads a request up to the end of its headers, and returns its first line.
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut r_stream = BufReader::new(stream.take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    r_stream.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if r_stream.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request read, headers included. Anything after it is ignored.
+const MAX_REQUEST_LEN: u64 = 8192;
+
+fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Live state of the server, as returned by `GET /state`.
+#[derive(Serialize, Debug)]
+struct Snapshot {
+    cameras: Vec<CameraSnapshot>,
+    /// Limit of each road and the camera at each of its miles.
+    roads: BTreeMap<u16, Road>,
+    dispatchers: Vec<DispatcherSnapshot>,
+    /// Tickets waiting for a dispatcher of their road.
+    pending: BTreeMap<u16, Vec<Ticket>>,
+    /// Sightings remembered for each plate, across all roads.
+    observations: BTreeMap<String, usize>,
+    tickets_per_day: BTreeMap<u32, usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct CameraSnapshot {
+    peer_addr: SocketAddr,
+    #[serde(flatten)]
+    i_am_camera: IAmCamera,
+}
+
+#[derive(Serialize, Debug)]
+struct DispatcherSnapshot {
+    peer_addr: SocketAddr,
+    roads: Vec<u16>,
+}
+
+impl Snapshot {
+    /// Copies the state out, holding one lock at a time so that connections are not held up for long.
+    async fn take(state: &State) -> Snapshot {
+        let roads = state.roads.lock().await;
+        let mut cameras = roads
+            .cameras()
+            .iter()
+            .map(|(peer_addr, i_am_camera)| CameraSnapshot {
+                peer_addr: *peer_addr,
+                i_am_camera: *i_am_camera,
+            })
+            .collect::<Vec<CameraSnapshot>>();
+        cameras.sort_by_key(|camera| camera.peer_addr);
+        let layouts = roads.layouts().clone();
+        drop(roads);
+        let dispatchers = state.dispatchers.lock().await;
+        let mut dispatcher_snapshots = dispatchers
+            .roads_by_dispatcher()
+            .into_iter()
+            .map(|(peer_addr, roads)| DispatcherSnapshot { peer_addr, roads })
+            .collect::<Vec<DispatcherSnapshot>>();
+        dispatcher_snapshots.sort_by_key(|dispatcher| dispatcher.peer_addr);
+        let pending = dispatchers
+            .pending()
+            .iter()
+            .map(|(road, tickets)| {
+                let tickets = tickets.iter().map(|issued| issued.ticket.clone());
+                (*road, tickets.collect())
+            })
+            .collect();
+        drop(dispatchers);
+        let mut observations = BTreeMap::new();
+        for (_, plate, _) in state.observations.lock().await.sightings() {
+            *observations.entry(plate.to_owned()).or_default() += 1;
+        }
+        let tickets_per_day = state.ledger.lock().await.tickets_per_day();
+        Snapshot {
+            cameras,
+            roads: layouts,
+            dispatchers: dispatcher_snapshots,
+            pending,
+            observations,
+            tickets_per_day,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::CameraClient;
+    use crate::client::DispatcherClient;
+    use crate::config::Config;
+    use crate::protocol::IAmCamera;
+    use crate::protocol::IAmDispatcher;
+    use crate::protocol::Plate;
+    use crate::server::Server;
+    use crate::test_util::serve;
+    use serde_json::json;
+    use serde_json::Value;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio::task::spawn;
+    async fn start_server() -> (SocketAddr, SocketAddr) {
+        let server = Server::new(Config::default()).await.unwrap();
+        let addr = serve(server.clone()).await;
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = admin_listener.local_addr().unwrap();
+        spawn(server.serve_admin(admin_listener));
+        (addr, admin_addr)
+    }
+    async fn get(admin_addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(admin_addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+    #[tokio::test]
+    async fn state_test() {
+        let (addr, admin_addr) = start_server().await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![124] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        let i_am_camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let i_am_camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 60,
+        };
+        let mut camera1 = CameraClient::connect(addr, i_am_camera1).await.unwrap();
+        let mut camera2 = CameraClient::connect(addr, i_am_camera2).await.unwrap();
+        let plate = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        camera1.send_plate(plate).await.unwrap();
+        // Heartbeats are only started once every earlier message of the connection was handled.
+        camera1.want_heartbeat(1).await.unwrap();
+        camera1.recv().await.unwrap().unwrap();
+        let plate = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 45,
+        };
+        camera2.send_plate(plate).await.unwrap();
+        camera2.want_heartbeat(1).await.unwrap();
+        camera2.recv().await.unwrap().unwrap();
+        dispatcher.want_heartbeat(1).await.unwrap();
+        dispatcher.recv().await.unwrap().unwrap();
+        let response = get(admin_addr, "/state").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let state = serde_json::from_str::<Value>(body).unwrap();
+        let cameras = state["cameras"].as_array().unwrap();
+        assert_eq!(cameras.len(), 2);
+        let mut miles = cameras
+            .iter()
+            .map(|camera| camera["mile"].as_u64().unwrap())
+            .collect::<Vec<u64>>();
+        miles.sort_unstable();
+        assert_eq!(miles, [8, 9]);
+        assert_eq!(cameras[0]["road"], 123);
+        assert_eq!(cameras[0]["limit"], 60);
+        assert_eq!(state["roads"]["123"]["limit"], 60);
+        let road_cameras = state["roads"]["123"]["cameras"].as_object().unwrap();
+        assert_eq!(road_cameras.keys().collect::<Vec<&String>>(), ["8", "9"]);
+        assert_eq!(state["dispatchers"][0]["roads"], json!([124]));
+        let ticket = json!({
+            "plate": "UN1X",
+            "road": 123,
+            "mile1": 8,
+            "timestamp1": 0,
+            "mile2": 9,
+            "timestamp2": 45,
+            "speed": 8000,
+        });
+        assert_eq!(state["pending"], json!({ "123": [ticket] }));
+        assert_eq!(state["observations"], json!({ "UN1X": 2 }));
+        assert_eq!(state["tickets_per_day"], json!({ "0": 1 }));
+    }
+    #[tokio::test]
+    async fn metrics_test() {
+        let (addr, admin_addr) = start_server().await;
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+        let plate = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        camera.send_plate(plate).await.unwrap();
+        camera.want_heartbeat(0).await.unwrap();
+        camera.want_heartbeat(0).await.unwrap();
+        camera.recv().await.unwrap().unwrap();
+        let response = get(admin_addr, "/metrics").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let lines = body.lines().collect::<Vec<&str>>();
+        assert!(lines.contains(&"speed_daemon_messages_decoded_total{type=\"i_am_camera\"} 1"));
+        assert!(lines.contains(&"speed_daemon_messages_decoded_total{type=\"plate\"} 1"));
+        assert!(lines.contains(&"speed_daemon_messages_decoded_total{type=\"want_heartbeat\"} 2"));
+        assert!(
+            lines.contains(&"speed_daemon_protocol_errors_total{kind=\"duplicate_heartbeat\"} 1")
+        );
+        assert!(lines.contains(&"speed_daemon_tickets_generated_total 0"));
+        assert!(lines.contains(&"speed_daemon_pending_tickets 0"));
+    }
+    #[tokio::test]
+    async fn not_found_test() {
+        let (_, admin_addr) = start_server().await;
+        let response = get(admin_addr, "/nope").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config::AuditFormat;
+use crate::metrics::Metrics;
+use crate::protocol::Ticket;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::spawn;
+use tokio::task::spawn_blocking;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// A ticket emitted by the server, along with the limit it broke and what became of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    #[serde(flatten)]
+    pub ticket: Ticket,
+    pub limit: u16,
+    pub status: TicketStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TicketStatus {
+    /// Written to a dispatcher of the road, whether straight away or once it connected.
+    Delivered,
+    /// Waiting for a dispatcher of the road to connect, upon which another entry says it was delivered.
+    Queued,
+    /// Dropped because the car was already ticketed on one of the days it spans.
+    Suppressed,
+}
+
+impl TicketStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            TicketStatus::Delivered => "delivered",
+            TicketStatus::Queued => "queued",
+            TicketStatus::Suppressed => "suppressed",
+        }
+    }
+}
+
+/// Destination of the audit trail, which receives every ticket the server emits.
+pub trait TicketSink: Debug + Send {
+    fn write(&mut self, entry: &AuditEntry) -> io::Result<()>;
+}
+
+/// Writes one CSV row per ticket.
+#[derive(Debug)]
+pub struct CsvSink<W> {
+    writer: W,
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Writes the header row first if `header` is set, which is not wanted when appending to an existing file.
+    pub fn new(mut writer: W, header: bool) -> io::Result<Self> {
+        if header {
+            writer.write_all(CSV_HEADER.as_bytes())?;
+            writer.flush()?;
+        }
+        Ok(CsvSink { writer })
+    }
+}
+
+const CSV_HEADER: &str = "plate,road,limit,mile1,timestamp1,mile2,timestamp2,speed,status\n";
+
+impl<W: Write + Debug + Send> TicketSink for CsvSink<W> {
+    fn write(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let ticket = &entry.ticket;
+        let row = format!(
+            "{},{},{},{},{},{},{},{},{}\n",
+            csv_field(&ticket.plate),
+            ticket.road,
+            entry.limit,
+            ticket.mile1,
+            ticket.timestamp1,
+            ticket.mile2,
+            ticket.timestamp2,
+            ticket.speed,
+            entry.status.as_str(),
+        );
+        self.writer.write_all(row.as_bytes())?;
+        self.writer.flush()
+    }
+}
+
+/// Quotes a field if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes one JSON object per line and per ticket.
+#[derive(Debug)]
+pub struct JsonLinesSink<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink { writer }
+    }
+}
+
+impl<W: Write + Debug + Send> TicketSink for JsonLinesSink<W> {
+    fn write(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()
+    }
+}
+
+/// Opens the audit file at `path` for appending, creating it if needed.
+pub(crate) fn open(path: &Path, format: AuditFormat) -> io::Result<Box<dyn TicketSink>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let sink: Box<dyn TicketSink> = match format {
+        AuditFormat::Csv => {
+            let header = file.metadata()?.len() == 0;
+            Box::new(CsvSink::new(file, header)?)
+        }
+        AuditFormat::JsonLines => Box::new(JsonLinesSink::new(file)),
+    };
+    Ok(sink)
+}
+
+/// Feeds a sink from a task of its own, which writes off the threads of the runtime, so that a slow or failing
+/// audit trail holds up no ticket.
+#[derive(Debug)]
+pub(crate) struct AuditWriter {
+    sender: UnboundedSender<AuditEntry>,
+    task: JoinHandle<()>,
+}
+
+impl AuditWriter {
+    pub(crate) fn spawn(sink: Box<dyn TicketSink>, metrics: Arc<Metrics>) -> AuditWriter {
+        let (sender, receiver) = unbounded_channel();
+        let task = spawn(write_entries(sink, receiver, metrics));
+        AuditWriter { sender, task }
+    }
+
+    pub(crate) fn write(&self, entry: AuditEntry) {
+        let _ = self.sender.send(entry);
+    }
+
+    /// Waits for the entries sent so far to be written.
+    pub(crate) async fn close(self) {
+        drop(self.sender);
+        let _ = self.task.await;
+    }
+}
+
+/// Writes entries in order until every sender is dropped. Entries that cannot be written are logged and counted.
+async fn write_entries(
+    mut sink: Box<dyn TicketSink>,
+    mut receiver: UnboundedReceiver<AuditEntry>,
+    metrics: Arc<Metrics>,
+) {
+    while let Some(entry) = receiver.recv().await {
+        let written = spawn_blocking(move || {
+            let result = sink.write(&entry);
+            (sink, entry, result)
+        });
+        let Ok((returned, entry, result)) = written.await else {
+            return;
+        };
+        sink = returned;
+        if let Err(err) = result {
+            warn!(%err, plate = entry.ticket.plate, "failed to write an audit entry");
+            metrics.audit_error();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditEntry;
+    use crate::audit::CsvSink;
+    use crate::audit::JsonLinesSink;
+    use crate::audit::TicketSink;
+    use crate::audit::TicketStatus;
+    use crate::protocol::Ticket;
+    fn entry(plate: &str, status: TicketStatus) -> AuditEntry {
+        let ticket = Ticket {
+            plate: plate.to_owned(),
+            road: 123,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        };
+        AuditEntry {
+            ticket,
+            limit: 60,
+            status,
+        }
+    }
+    #[test]
+    fn csv_test() {
+        let mut sink = CsvSink::new(Vec::new(), true).unwrap();
+        sink.write(&entry("UN1X", TicketStatus::Delivered)).unwrap();
+        sink.write(&entry("A,\"B\"", TicketStatus::Suppressed))
+            .unwrap();
+        let csv = String::from_utf8(sink.writer).unwrap();
+        let expected = "plate,road,limit,mile1,timestamp1,mile2,timestamp2,speed,status\n\
+            UN1X,123,60,8,0,9,45,8000,delivered\n\
+            \"A,\"\"B\"\"\",123,60,8,0,9,45,8000,suppressed\n";
+        assert_eq!(csv, expected);
+    }
+    #[test]
+    fn json_lines_test() {
+        let mut sink = JsonLinesSink::new(Vec::new());
+        sink.write(&entry("UN1X", TicketStatus::Queued)).unwrap();
+        sink.write(&entry("RE05BKG", TicketStatus::Delivered))
+            .unwrap();
+        let json = String::from_utf8(sink.writer).unwrap();
+        let lines = json.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines[0],
+            r#"{"plate":"UN1X","road":123,"mile1":8,"timestamp1":0,"mile2":9,"timestamp2":45,"speed":8000,"limit":60,"status":"queued"}"#
+        );
+        assert!(lines[1].starts_with(r#"{"plate":"RE05BKG","#));
+        assert_eq!(lines.len(), 2);
+    }
+}
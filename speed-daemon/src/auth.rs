@@ -0,0 +1,95 @@
+use crate::protocol::IAmCamera;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::fs;
+use tokio::io;
+
+/// Tokens that cameras present before identifying, each allowing the cameras holding it at some miles of some
+/// roads.
+#[derive(Debug, Default)]
+pub(crate) struct Tokens {
+    allowed: HashMap<String, HashSet<(u16, u16)>>,
+}
+
+/// Token file, a TOML file with one `[[camera]]` table per token and mile allowed.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenFile {
+    #[serde(default)]
+    camera: Vec<CameraToken>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraToken {
+    token: String,
+    road: u16,
+    mile: u16,
+}
+
+impl Tokens {
+    pub(crate) async fn load(path: &Path) -> io::Result<Tokens> {
+        let str = fs::read_to_string(path).await?;
+        Tokens::parse(&str).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn parse(str: &str) -> Result<Tokens, toml::de::Error> {
+        let token_file = toml::from_str::<TokenFile>(str)?;
+        let mut tokens = Tokens::default();
+        for camera in token_file.camera {
+            tokens
+                .allowed
+                .entry(camera.token)
+                .or_default()
+                .insert((camera.road, camera.mile));
+        }
+        Ok(tokens)
+    }
+
+    pub(crate) fn allows(&self, token: &str, i_am_camera: &IAmCamera) -> bool {
+        self.allowed
+            .get(token)
+            .is_some_and(|allowed| allowed.contains(&(i_am_camera.road, i_am_camera.mile)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::Tokens;
+    use crate::protocol::IAmCamera;
+    #[test]
+    fn allows_test() {
+        let str = r#"
+            [[camera]]
+            token = "s3cr3t"
+            road = 123
+            mile = 8
+
+            [[camera]]
+            token = "s3cr3t"
+            road = 123
+            mile = 9
+
+            [[camera]]
+            token = "0th3r"
+            road = 368
+            mile = 8
+        "#;
+        let tokens = Tokens::parse(str).unwrap();
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 60,
+        };
+        assert!(tokens.allows("s3cr3t", &i_am_camera));
+        assert!(!tokens.allows("0th3r", &i_am_camera));
+        assert!(!tokens.allows("", &i_am_camera));
+        let i_am_camera = IAmCamera {
+            mile: 10,
+            ..i_am_camera
+        };
+        assert!(!tokens.allows("s3cr3t", &i_am_camera));
+    }
+}
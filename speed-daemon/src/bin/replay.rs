@@ -0,0 +1,206 @@
+use bytes::BytesMut;
+use clap::Parser;
+use speed_daemon::client::DispatcherClient;
+use speed_daemon::config::Config;
+use speed_daemon::protocol::IAmDispatcher;
+use speed_daemon::protocol::Request;
+use speed_daemon::protocol::ServerCodec;
+use speed_daemon::protocol::Ticket;
+use speed_daemon::recorder::list_captures;
+use speed_daemon::recorder::read_capture;
+use speed_daemon::recorder::Chunk;
+use speed_daemon::server;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::spawn;
+use tokio::task::JoinSet;
+use tokio::time::sleep_until;
+use tokio::time::timeout;
+use tokio::time::Instant;
+use tokio_util::codec::Decoder;
+
+/// Feeds the connections captured with `speed-daemon --record` back to a server and prints the tickets it issues,
+/// one CSV row per ticket in a stable order so that runs can be diffed.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Directory of the capture files.
+    captures: PathBuf,
+    /// Address of the server under test.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: SocketAddr,
+    /// Starts a server in this process instead of connecting to `addr`.
+    #[arg(long)]
+    in_process: bool,
+    /// TOML file with the settings of the server started with `--in-process`.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// How many times faster than recorded the bytes are sent. 0 sends them as fast as possible.
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Seconds to wait for another ticket once every capture was replayed.
+    #[arg(long, default_value_t = 2)]
+    timeout: u64,
+}
+
+/// A captured connection and the roads named in it.
+#[derive(Debug)]
+struct Capture {
+    chunks: Vec<Chunk>,
+    roads: Vec<u16>,
+    is_dispatcher: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args = Args::parse();
+    if args.speed.is_nan() || args.speed < 0.0 {
+        Err("the speed cannot be negative")?;
+    }
+    let addr = if args.in_process {
+        let config = match &args.config {
+            Some(path) => Config::load(path).await?,
+            None => Config::default(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        spawn(server::run(listener, config));
+        addr
+    } else {
+        args.addr
+    };
+    let mut captures = Vec::new();
+    for path in list_captures(&args.captures).await? {
+        captures.push(parse_capture(read_capture(&path).await?));
+    }
+    // Captured dispatchers are not replayed, since they would take the tickets away from ours.
+    let roads = captures
+        .iter()
+        .flat_map(|capture| capture.roads.iter().copied())
+        .collect::<BTreeSet<u16>>()
+        .into_iter()
+        .collect::<Vec<u16>>();
+    let (ticket_sender, mut ticket_receiver) = unbounded_channel();
+    for roads in roads.chunks(usize::from(u8::MAX)) {
+        let i_am_dispatcher = IAmDispatcher {
+            roads: roads.to_vec(),
+        };
+        let client = DispatcherClient::connect(addr, i_am_dispatcher).await?;
+        spawn(receive_tickets(client, ticket_sender.clone()));
+    }
+    let captures = captures
+        .into_iter()
+        .filter(|capture| !capture.is_dispatcher)
+        .collect::<Vec<Capture>>();
+    let first_time = captures
+        .iter()
+        .filter_map(|capture| capture.chunks.first())
+        .map(|chunk| chunk.time)
+        .min()
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let start = Instant::now();
+    let mut connections = JoinSet::new();
+    for capture in captures {
+        connections.spawn(replay(addr, capture, start, first_time, args.speed));
+    }
+    while let Some(replayed) = connections.join_next().await {
+        replayed??;
+    }
+    let mut tickets = Vec::new();
+    let wait = Duration::from_secs(args.timeout);
+    while let Ok(Some(ticket)) = timeout(wait, ticket_receiver.recv()).await {
+        tickets.push(ticket);
+    }
+    tickets.sort_by_key(|ticket: &Ticket| {
+        (
+            ticket.plate.clone(),
+            ticket.road,
+            ticket.timestamp1,
+            ticket.mile1,
+            ticket.timestamp2,
+            ticket.mile2,
+        )
+    });
+    println!("plate,road,mile1,timestamp1,mile2,timestamp2,speed");
+    for ticket in tickets {
+        println!(
+            "{},{},{},{},{},{},{}",
+            ticket.plate,
+            ticket.road,
+            ticket.mile1,
+            ticket.timestamp1,
+            ticket.mile2,
+            ticket.timestamp2,
+            ticket.speed
+        );
+    }
+    Ok(())
+}
+
+/// Decodes the requests of a capture to find out which roads it concerns.
+fn parse_capture(chunks: Vec<Chunk>) -> Capture {
+    let mut bytes = chunks
+        .iter()
+        .flat_map(|chunk| chunk.bytes.iter().copied())
+        .collect::<BytesMut>();
+    let mut roads = Vec::new();
+    let mut is_dispatcher = false;
+    while let Ok(Some(request)) = ServerCodec.decode(&mut bytes) {
+        match request {
+            Request::IAmCamera(i_am_camera) => roads.push(i_am_camera.road),
+            Request::IAmDispatcher(i_am_dispatcher) => {
+                roads.extend(i_am_dispatcher.roads);
+                is_dispatcher = true;
+            }
+            Request::Authenticate(_) | Request::WantHeartbeat(_) | Request::Plate(_) => {}
+        }
+    }
+    Capture {
+        chunks,
+        roads,
+        is_dispatcher,
+    }
+}
+
+/// Sends the chunks of a capture at the same times relative to `first_time` as they were recorded, divided by
+/// `speed`.
+async fn replay(
+    addr: SocketAddr,
+    capture: Capture,
+    start: Instant,
+    first_time: SystemTime,
+    speed: f64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stream = TcpStream::connect(addr).await?;
+    let (mut r_stream, mut w_stream) = stream.into_split();
+    // Responses are of no interest, but they must be read for the server not to block on writing them.
+    spawn(async move {
+        let mut bytes = Vec::new();
+        let _ = r_stream.read_to_end(&mut bytes).await;
+    });
+    for chunk in capture.chunks {
+        if speed > 0.0 {
+            let offset = chunk.time.duration_since(first_time).unwrap_or_default();
+            sleep_until(start + offset.div_f64(speed)).await;
+        }
+        w_stream.write_all(&chunk.bytes).await?;
+    }
+    Ok(())
+}
+
+async fn receive_tickets(mut client: DispatcherClient, sender: UnboundedSender<Ticket>) {
+    while let Some(Ok(ticket)) = client.recv_ticket().await {
+        if sender.send(ticket).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use clap::Parser;
+use speed_daemon::client::CameraClient;
+use speed_daemon::client::DispatcherClient;
+use speed_daemon::config::Config;
+use speed_daemon::protocol::IAmCamera;
+use speed_daemon::protocol::IAmDispatcher;
+use speed_daemon::protocol::Plate;
+use speed_daemon::protocol::Ticket;
+use speed_daemon::server;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+use tokio::task::spawn;
+use tokio::task::JoinSet;
+use tokio::time::timeout_at;
+
+/// Drives synthetic cars past simulated cameras and checks that speed-daemon issues exactly the expected tickets.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Address of the server under test.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: SocketAddr,
+    /// Starts a server in this process instead of connecting to `addr`.
+    #[arg(long)]
+    in_process: bool,
+    #[arg(long, default_value_t = 1)]
+    roads: u16,
+    #[arg(long, default_value_t = 5)]
+    cameras_per_road: u16,
+    /// Miles between consecutive cameras of a road.
+    #[arg(long, default_value_t = 10)]
+    mile_spacing: u16,
+    #[arg(long, default_value_t = 60)]
+    limit: u16,
+    /// Number of dispatchers, which split the roads between them.
+    #[arg(long, default_value_t = 1)]
+    dispatchers: u16,
+    #[arg(long, default_value_t = 100)]
+    cars: u32,
+    /// Speeds in miles per hour, assigned to the cars in turn.
+    #[arg(long, value_delimiter = ',', default_value = "50,60,70,90")]
+    speeds: Vec<u16>,
+    /// Seconds to wait for the expected tickets once every plate was sent.
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Car {
+    plate: String,
+    road: u16,
+    speed: u16,
+}
+
+/// Time at which each plate was sent by the camera at each mile, used to measure ticket latency.
+type SentTimes = Arc<Mutex<HashMap<(String, u16), Instant>>>;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args = Args::parse();
+    if args.roads == 0
+        || args.dispatchers == 0
+        || args.cameras_per_road < 2
+        || args.speeds.is_empty()
+        || args.speeds.contains(&0)
+    {
+        Err("at least one road, one dispatcher, two cameras per road and positive speeds are needed")?;
+    }
+    let Some(last_mile) = (args.cameras_per_road - 1).checked_mul(args.mile_spacing) else {
+        Err("the cameras of a road do not fit within 65535 miles")?
+    };
+    // Every car must drive within a single day, or it could get a ticket per day.
+    let slowest = args.speeds.iter().copied().min().unwrap_or_default();
+    if timestamp(slowest, last_mile) >= 86400 {
+        Err("the slowest cars would pass the last camera after the first day")?;
+    }
+    let addr = if args.in_process {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        spawn(server::run(listener, Config::default()));
+        addr
+    } else {
+        args.addr
+    };
+    let cars = build_cars(&args);
+    let expected = cars
+        .iter()
+        .filter(|car| is_speeding(&args, car))
+        .map(|car| car.plate.clone())
+        .collect::<HashSet<String>>();
+    let sent_times = SentTimes::default();
+    let (ticket_sender, mut ticket_receiver) = unbounded_channel();
+    for dispatcher in 0..args.dispatchers {
+        let roads = (0..args.roads)
+            .filter(|road| road % args.dispatchers == dispatcher)
+            .collect::<Vec<u16>>();
+        let i_am_dispatcher = IAmDispatcher { roads };
+        let client = DispatcherClient::connect(addr, i_am_dispatcher).await?;
+        spawn(receive_tickets(client, ticket_sender.clone()));
+    }
+    let start = Instant::now();
+    let mut cameras = JoinSet::new();
+    for road in 0..args.roads {
+        for camera in 0..args.cameras_per_road {
+            let i_am_camera = IAmCamera {
+                road,
+                mile: camera * args.mile_spacing,
+                limit: args.limit,
+            };
+            let client = CameraClient::connect(addr, i_am_camera).await?;
+            let cars = cars
+                .iter()
+                .filter(|car| car.road == road)
+                .cloned()
+                .collect::<Vec<Car>>();
+            cameras.spawn(send_plates(client, i_am_camera, cars, sent_times.clone()));
+        }
+    }
+    let mut plates = 0;
+    while let Some(sent) = cameras.join_next().await {
+        plates += sent??;
+    }
+    let sending_time = start.elapsed();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(args.timeout);
+    let mut received = HashSet::new();
+    let mut unexpected = Vec::new();
+    let mut latencies = Vec::new();
+    loop {
+        // Once every expected ticket came, tickets are still received until none come for a while, so that
+        // duplicate and unexpected ones are caught too.
+        let wait_until = if received.len() < expected.len() {
+            deadline
+        } else {
+            deadline.min(tokio::time::Instant::now() + QUIET_PERIOD)
+        };
+        let Ok(Some((ticket, received_at))) = timeout_at(wait_until, ticket_receiver.recv()).await
+        else {
+            break;
+        };
+        let sent_times = sent_times.lock().await;
+        let sent_at = [ticket.mile1, ticket.mile2]
+            .into_iter()
+            .filter_map(|mile| sent_times.get(&(ticket.plate.clone(), mile)))
+            .max();
+        if let Some(sent_at) = sent_at {
+            latencies.push(received_at.saturating_duration_since(*sent_at));
+        }
+        if !expected.contains(&ticket.plate)
+            || !is_valid_ticket(&args, &ticket)
+            || !received.insert(ticket.plate.clone())
+        {
+            unexpected.push(ticket);
+        }
+    }
+    let missing = expected.difference(&received).count();
+    report(
+        plates,
+        sending_time,
+        &expected,
+        &received,
+        &unexpected,
+        missing,
+        latencies,
+    );
+    if missing > 0 || !unexpected.is_empty() {
+        Err("tickets did not match the expected ones")?;
+    }
+    Ok(())
+}
+
+/// Time without tickets after which no more are expected, once every expected one came.
+const QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+fn build_cars(args: &Args) -> Vec<Car> {
+    (0..args.cars)
+        .map(|car| Car {
+            plate: format!("SIM{car:05}"),
+            road: (car % u32::from(args.roads)) as u16,
+            speed: args.speeds[car as usize % args.speeds.len()],
+        })
+        .collect()
+}
+
+/// Time at which a car driving at `speed` from mile 0 at time 0 passes a given mile.
+fn timestamp(speed: u16, mile: u16) -> u32 {
+    (u64::from(mile) * 3600 / u64::from(speed)) as u32
+}
+
+/// Average speed between two sightings in hundredths of miles per hour, computed like the server does.
+fn average_speed(mile1: u16, timestamp1: u32, mile2: u16, timestamp2: u32) -> Option<u64> {
+    let distance = u64::from(mile1.abs_diff(mile2));
+    let time = u64::from(timestamp1.abs_diff(timestamp2));
+    if time == 0 {
+        return None;
+    }
+    Some(distance * 3600 * 100 / time)
+}
+
+fn is_over_limit(args: &Args, speed: u64) -> bool {
+    speed >= u64::from(args.limit) * 100 + 50
+}
+
+/// Whether any two adjacent cameras see a car going over the limit. Every car has its own plate and drives within
+/// a single day, so such a car must get exactly one ticket.
+fn is_speeding(args: &Args, car: &Car) -> bool {
+    (1..args.cameras_per_road).any(|camera| {
+        let mile1 = (camera - 1) * args.mile_spacing;
+        let mile2 = camera * args.mile_spacing;
+        let timestamp1 = timestamp(car.speed, mile1);
+        let timestamp2 = timestamp(car.speed, mile2);
+        average_speed(mile1, timestamp1, mile2, timestamp2)
+            .is_some_and(|speed| is_over_limit(args, speed))
+    })
+}
+
+fn is_valid_ticket(args: &Args, ticket: &Ticket) -> bool {
+    let speed = average_speed(
+        ticket.mile1,
+        ticket.timestamp1,
+        ticket.mile2,
+        ticket.timestamp2,
+    );
+    speed.is_some_and(|speed| u64::from(ticket.speed) == speed.min(u16::MAX.into()))
+        && is_over_limit(args, ticket.speed.into())
+}
+
+async fn send_plates(
+    mut client: CameraClient,
+    i_am_camera: IAmCamera,
+    cars: Vec<Car>,
+    sent_times: SentTimes,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    for car in &cars {
+        let plate = Plate {
+            plate: car.plate.clone(),
+            timestamp: timestamp(car.speed, i_am_camera.mile),
+        };
+        client.send_plate(plate).await?;
+        let key = (car.plate.clone(), i_am_camera.mile);
+        sent_times.lock().await.insert(key, Instant::now());
+    }
+    Ok(cars.len())
+}
+
+async fn receive_tickets(mut client: DispatcherClient, sender: UnboundedSender<(Ticket, Instant)>) {
+    while let Some(Ok(ticket)) = client.recv_ticket().await {
+        if sender.send((ticket, Instant::now())).is_err() {
+            return;
+        }
+    }
+}
+
+fn report(
+    plates: usize,
+    sending_time: Duration,
+    expected: &HashSet<String>,
+    received: &HashSet<String>,
+    unexpected: &[Ticket],
+    missing: usize,
+    mut latencies: Vec<Duration>,
+) {
+    let throughput = plates as f64 / sending_time.as_secs_f64();
+    println!("plates sent:        {plates} in {sending_time:?} ({throughput:.0} plates/s)");
+    println!("tickets expected:   {}", expected.len());
+    println!("tickets received:   {}", received.len());
+    println!("tickets missing:    {missing}");
+    println!("tickets unexpected: {}", unexpected.len());
+    for ticket in unexpected {
+        println!("  {ticket:?}");
+    }
+    latencies.sort();
+    if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+        let median = latencies[latencies.len() / 2];
+        let p99 = latencies[latencies.len() * 99 / 100];
+        println!("latency:            min {min:?}, median {median:?}, p99 {p99:?}, max {max:?}");
+    }
+}
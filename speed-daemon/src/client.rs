@@ -0,0 +1,195 @@
+use crate::protocol::Authenticate;
+use crate::protocol::ClientCodec;
+use crate::protocol::IAmCamera;
+use crate::protocol::IAmDispatcher;
+use crate::protocol::Plate;
+use crate::protocol::ProtocolError;
+use crate::protocol::Request;
+use crate::protocol::Response;
+use crate::protocol::Ticket;
+use crate::protocol::WantHeartbeat;
+use futures::SinkExt;
+use futures::StreamExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+use tokio_util::codec::Framed;
+
+/// Connection of a camera, which identifies itself on connection and then reports the plates it sees.
+pub struct CameraClient<S = TcpStream> {
+    framed: Framed<S, ClientCodec>,
+}
+
+impl CameraClient {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        i_am_camera: IAmCamera,
+    ) -> Result<Self, ProtocolError> {
+        let stream = TcpStream::connect(addr).await?;
+        Self::new(stream, i_am_camera).await
+    }
+
+    /// Connects to a server that requires cameras to authenticate.
+    pub async fn connect_with_token(
+        addr: impl ToSocketAddrs,
+        token: String,
+        i_am_camera: IAmCamera,
+    ) -> Result<Self, ProtocolError> {
+        let stream = TcpStream::connect(addr).await?;
+        Self::with_token(stream, token, i_am_camera).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> CameraClient<S> {
+    pub async fn new(stream: S, i_am_camera: IAmCamera) -> Result<Self, ProtocolError> {
+        let mut framed = Framed::new(stream, ClientCodec);
+        framed.send(Request::IAmCamera(i_am_camera)).await?;
+        Ok(CameraClient { framed })
+    }
+
+    /// Presents `token` before identifying, as servers that require cameras to authenticate expect.
+    pub async fn with_token(
+        stream: S,
+        token: String,
+        i_am_camera: IAmCamera,
+    ) -> Result<Self, ProtocolError> {
+        let mut framed = Framed::new(stream, ClientCodec);
+        framed
+            .send(Request::Authenticate(Authenticate { token }))
+            .await?;
+        framed.send(Request::IAmCamera(i_am_camera)).await?;
+        Ok(CameraClient { framed })
+    }
+
+    pub async fn send_plate(&mut self, plate: Plate) -> Result<(), ProtocolError> {
+        self.framed.send(Request::Plate(plate)).await
+    }
+
+    pub async fn want_heartbeat(&mut self, interval: u32) -> Result<(), ProtocolError> {
+        let want_heartbeat = WantHeartbeat { interval };
+        self.framed
+            .send(Request::WantHeartbeat(want_heartbeat))
+            .await
+    }
+
+    /// Waits for the next message from the server, returning `None` once the server hangs up.
+    pub async fn recv(&mut self) -> Option<Result<Response, ProtocolError>> {
+        self.framed.next().await
+    }
+}
+
+/// Connection of a dispatcher, which announces its roads on connection and then receives their tickets.
+pub struct DispatcherClient<S = TcpStream> {
+    framed: Framed<S, ClientCodec>,
+}
+
+impl DispatcherClient {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        i_am_dispatcher: IAmDispatcher,
+    ) -> Result<Self, ProtocolError> {
+        let stream = TcpStream::connect(addr).await?;
+        Self::new(stream, i_am_dispatcher).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> DispatcherClient<S> {
+    pub async fn new(stream: S, i_am_dispatcher: IAmDispatcher) -> Result<Self, ProtocolError> {
+        let mut framed = Framed::new(stream, ClientCodec);
+        framed.send(Request::IAmDispatcher(i_am_dispatcher)).await?;
+        Ok(DispatcherClient { framed })
+    }
+
+    pub async fn want_heartbeat(&mut self, interval: u32) -> Result<(), ProtocolError> {
+        let want_heartbeat = WantHeartbeat { interval };
+        self.framed
+            .send(Request::WantHeartbeat(want_heartbeat))
+            .await
+    }
+
+    /// Waits for the next message from the server, returning `None` once the server hangs up.
+    pub async fn recv(&mut self) -> Option<Result<Response, ProtocolError>> {
+        self.framed.next().await
+    }
+
+    /// Waits for the next ticket, skipping heartbeats. An error message from the server is returned as
+    /// [`ProtocolError::Rejected`].
+    pub async fn recv_ticket(&mut self) -> Option<Result<Ticket, ProtocolError>> {
+        loop {
+            match self.recv().await? {
+                Ok(Response::Ticket(ticket)) => return Some(Ok(ticket)),
+                Ok(Response::Heartbeat) => continue,
+                Ok(Response::Error(msg)) => return Some(Err(ProtocolError::Rejected(msg))),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::CameraClient;
+    use crate::client::DispatcherClient;
+    use crate::config::Config;
+    use crate::protocol::IAmCamera;
+    use crate::protocol::IAmDispatcher;
+    use crate::protocol::Plate;
+    use crate::protocol::Response;
+    use crate::test_util::start_server;
+    #[tokio::test]
+    async fn ticket_test() {
+        let addr = start_server(Config::default()).await;
+        let i_am_camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let i_am_camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 60,
+        };
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut camera1 = CameraClient::connect(addr, i_am_camera1).await.unwrap();
+        let mut camera2 = CameraClient::connect(addr, i_am_camera2).await.unwrap();
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        let plate1 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        let plate2 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 45,
+        };
+        camera1.send_plate(plate1).await.unwrap();
+        camera2.send_plate(plate2).await.unwrap();
+        let ticket = dispatcher.recv_ticket().await.unwrap().unwrap();
+        assert_eq!(ticket.plate, "UN1X".to_owned());
+        assert_eq!(ticket.road, 123);
+        assert_eq!(ticket.mile1, 8);
+        assert_eq!(ticket.timestamp1, 0);
+        assert_eq!(ticket.mile2, 9);
+        assert_eq!(ticket.timestamp2, 45);
+        assert_eq!(ticket.speed, 8000);
+    }
+    #[tokio::test]
+    async fn duplicate_heartbeat_test() {
+        let addr = start_server(Config::default()).await;
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+        camera.want_heartbeat(0).await.unwrap();
+        camera.want_heartbeat(0).await.unwrap();
+        let Some(Ok(Response::Error(msg))) = camera.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(msg, "heartbeat already requested".to_owned());
+        assert!(camera.recv().await.is_none());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::policy::TimeOfDayLimit;
+use crate::tickets::SPEED_TOLERANCE;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::io;
+
+/// Settings of a server, read from a TOML file. Missing keys take their default value.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address on which cameras and dispatchers connect.
+    pub listen: SocketAddr,
+    /// Connections served at once, at least one. Further clients wait to be accepted.
+    pub max_connections: NonZeroUsize,
+    /// Longest plate accepted from cameras. Longer plates are a protocol error.
+    pub max_plate_len: u8,
+    /// How far above the limit, in hundredths of miles per hour, a car has to go to get a ticket.
+    pub speed_tolerance: u16,
+    /// How far above the limit, in percent, a car has to go to get a ticket, in place of `speed_tolerance`.
+    pub percent_tolerance: Option<u16>,
+    /// Fewest miles between two sightings for them to give a ticket.
+    pub min_distance: Option<u16>,
+    /// Most seconds between two sightings for them to give a ticket.
+    pub max_duration: Option<u32>,
+    /// Limits replacing those of the cameras during parts of the day, in a `[[time_of_day_limits]]` table each.
+    pub time_of_day_limits: Vec<TimeOfDayLimit>,
+    /// Shortest heartbeat interval, in deciseconds, that clients get. Shorter ones are raised to it, except
+    /// for 0, which still means no heartbeats.
+    pub min_heartbeat_interval: u32,
+    /// File in which observations and tickets are saved to survive restarts.
+    pub journal: Option<PathBuf>,
+    /// Seconds before the latest sighting within which sightings are kept. Older ones are evicted, along with
+    /// those on days their plate was already ticketed, and sightings arriving older than that are dropped.
+    /// Without it, sightings may arrive up to a day late.
+    pub retention_window: Option<u32>,
+    /// Most sightings held in memory. Past it, the oldest are evicted even within the retention window.
+    pub max_observations: Option<usize>,
+    /// Address of the HTTP endpoint showing the live state of the server. It has no authentication, so it
+    /// should be a local address.
+    pub admin: Option<SocketAddr>,
+    /// File to which every ticket emitted is appended, whether it was delivered or not.
+    pub audit: Option<PathBuf>,
+    pub audit_format: AuditFormat,
+    /// Directory in which the bytes received on each connection are saved, to be replayed later. Camera tokens
+    /// are overwritten with `*`, and only the owner may read the files.
+    pub record: Option<PathBuf>,
+    /// Listener on which cameras and dispatchers connect over TLS, alongside the plaintext one.
+    pub tls: Option<TlsConfig>,
+    /// TOML file of the tokens that cameras must present, each for the roads and miles it allows. Without it,
+    /// any client may be a camera.
+    pub tokens: Option<PathBuf>,
+    /// Format of the logs written to standard error, whose verbosity is set by `RUST_LOG`.
+    pub log_format: LogFormat,
+}
+
+/// Address and credentials of the TLS listener.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub listen: SocketAddr,
+    /// PEM file of the certificate chain, starting with the certificate of the server.
+    pub cert: PathBuf,
+    /// PEM file of the private key of the certificate.
+    pub key: PathBuf,
+}
+
+/// Format of the ticket audit file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditFormat {
+    /// A header row followed by one row per ticket.
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// Format of the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    #[default]
+    Text,
+    /// One JSON object per event, with the fields of its spans.
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            max_connections: NonZeroUsize::new(10000).unwrap(),
+            max_plate_len: u8::MAX,
+            speed_tolerance: SPEED_TOLERANCE,
+            percent_tolerance: None,
+            min_distance: None,
+            max_duration: None,
+            time_of_day_limits: Vec::new(),
+            min_heartbeat_interval: 0,
+            journal: None,
+            retention_window: None,
+            max_observations: None,
+            admin: None,
+            audit: None,
+            audit_format: AuditFormat::default(),
+            record: None,
+            tls: None,
+            tokens: None,
+            log_format: LogFormat::default(),
+        }
+    }
+}
+
+impl Config {
+    pub async fn load(path: &Path) -> io::Result<Config> {
+        let str = fs::read_to_string(path).await?;
+        toml::from_str(&str).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AuditFormat;
+    use crate::config::Config;
+    use crate::config::LogFormat;
+    use crate::config::TlsConfig;
+    use crate::policy::TimeOfDayLimit;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    #[test]
+    fn parse_config_test() {
+        let str = r#"
+            listen = "127.0.0.1:9000"
+            max_plate_len = 10
+            speed_tolerance = 100
+            journal = "/var/lib/speed-daemon/journal"
+            audit_format = "json-lines"
+            log_format = "json"
+
+            [[time_of_day_limits]]
+            start = 79200
+            end = 21600
+            limit = 40
+
+            [tls]
+            listen = "0.0.0.0:9443"
+            cert = "/etc/speed-daemon/cert.pem"
+            key = "/etc/speed-daemon/key.pem"
+        "#;
+        let config = toml::from_str::<Config>(str).unwrap();
+        let expected = Config {
+            listen: SocketAddr::from(([127, 0, 0, 1], 9000)),
+            max_plate_len: 10,
+            speed_tolerance: 100,
+            journal: Some(PathBuf::from("/var/lib/speed-daemon/journal")),
+            audit_format: AuditFormat::JsonLines,
+            log_format: LogFormat::Json,
+            time_of_day_limits: vec![TimeOfDayLimit {
+                start: 79200,
+                end: 21600,
+                limit: 40,
+            }],
+            tls: Some(TlsConfig {
+                listen: SocketAddr::from(([0, 0, 0, 0], 9443)),
+                cert: PathBuf::from("/etc/speed-daemon/cert.pem"),
+                key: PathBuf::from("/etc/speed-daemon/key.pem"),
+            }),
+            ..Config::default()
+        };
+        assert_eq!(config, expected);
+    }
+    #[test]
+    fn parse_unknown_key_test() {
+        let str = "max_conections = 10";
+        assert!(toml::from_str::<Config>(str).is_err());
+    }
+    #[test]
+    fn parse_zero_max_connections_test() {
+        let str = "max_connections = 0";
+        assert!(toml::from_str::<Config>(str).is_err());
+    }
+}
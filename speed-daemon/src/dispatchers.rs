@@ -0,0 +1,237 @@
+use crate::protocol::Ticket;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+
+/// Connected dispatchers indexed by the roads they are responsible for, along with the tickets waiting for one.
+/// Tickets are handed to the connection of a dispatcher, which writes them, so that a dispatcher that stops
+/// reading holds up no one else.
+#[derive(Default, Debug)]
+pub(crate) struct Dispatchers {
+    senders: HashMap<SocketAddr, Sender<IssuedTicket>>,
+    roads: HashMap<u16, HashSet<SocketAddr>>,
+    pending: HashMap<u16, VecDeque<IssuedTicket>>,
+}
+
+/// A ticket on its way to a dispatcher, along with the limit it broke for the audit trail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IssuedTicket {
+    pub(crate) ticket: Ticket,
+    pub(crate) limit: u16,
+}
+
+/// Tickets handed to a dispatcher that its connection has yet to write, beyond which it is passed over.
+pub(crate) const TICKET_BUFFER: usize = 1024;
+
+impl Dispatchers {
+    /// Adds a dispatcher and hands it the tickets that were waiting for any of its roads.
+    pub(crate) fn register(
+        &mut self,
+        peer_addr: SocketAddr,
+        sender: Sender<IssuedTicket>,
+        roads: &[u16],
+    ) {
+        self.senders.insert(peer_addr, sender);
+        for road in roads {
+            self.roads.entry(*road).or_default().insert(peer_addr);
+        }
+        for road in roads {
+            self.flush(*road);
+        }
+    }
+
+    pub(crate) fn unregister(&mut self, peer_addr: SocketAddr) {
+        self.senders.remove(&peer_addr);
+        self.roads.retain(|_, peer_addrs| {
+            peer_addrs.remove(&peer_addr);
+            !peer_addrs.is_empty()
+        });
+    }
+
+    /// Removes a dispatcher that is going away and dispatches again the tickets it was handed but did not write.
+    /// Returns those that were queued for want of another dispatcher.
+    pub(crate) fn unregister_with(
+        &mut self,
+        peer_addr: SocketAddr,
+        mut receiver: Receiver<IssuedTicket>,
+    ) -> Vec<IssuedTicket> {
+        self.unregister(peer_addr);
+        receiver.close();
+        let mut queued = Vec::new();
+        while let Ok(issued) = receiver.try_recv() {
+            if !self.dispatch(issued.clone()) {
+                queued.push(issued);
+            }
+        }
+        queued
+    }
+
+    /// Hands a ticket to a dispatcher of its road, or queues it until one connects. Returns whether the ticket
+    /// was handed to a dispatcher.
+    pub(crate) fn dispatch(&mut self, issued: IssuedTicket) -> bool {
+        match self.deliver(issued) {
+            Ok(()) => true,
+            Err(issued) => {
+                self.queue(issued);
+                false
+            }
+        }
+    }
+
+    /// Roads of each connected dispatcher, in ascending order.
+    pub(crate) fn roads_by_dispatcher(&self) -> HashMap<SocketAddr, Vec<u16>> {
+        let mut roads_by_dispatcher = self
+            .senders
+            .keys()
+            .map(|peer_addr| (*peer_addr, Vec::new()))
+            .collect::<HashMap<SocketAddr, Vec<u16>>>();
+        for (road, peer_addrs) in &self.roads {
+            for peer_addr in peer_addrs {
+                if let Some(roads) = roads_by_dispatcher.get_mut(peer_addr) {
+                    roads.push(*road);
+                }
+            }
+        }
+        for roads in roads_by_dispatcher.values_mut() {
+            roads.sort_unstable();
+        }
+        roads_by_dispatcher
+    }
+
+    /// Tickets waiting for a dispatcher, by road.
+    pub(crate) fn pending(&self) -> &HashMap<u16, VecDeque<IssuedTicket>> {
+        &self.pending
+    }
+
+    /// Queues a ticket without trying to deliver it, as when recovering the tickets pending at the last shutdown.
+    pub(crate) fn queue(&mut self, issued: IssuedTicket) {
+        self.pending
+            .entry(issued.ticket.road)
+            .or_default()
+            .push_back(issued);
+    }
+
+    /// Hands the queued tickets of a road to its dispatchers in order, stopping at the first one that none of them
+    /// can take.
+    fn flush(&mut self, road: u16) {
+        let Some(mut tickets) = self.pending.remove(&road) else {
+            return;
+        };
+        while let Some(issued) = tickets.pop_front() {
+            if let Err(issued) = self.deliver(issued) {
+                tickets.push_front(issued);
+                break;
+            }
+        }
+        if !tickets.is_empty() {
+            self.pending.insert(road, tickets);
+        }
+    }
+
+    /// Hands a ticket to the first dispatcher of its road that has room for it, dropping the dispatchers that are
+    /// gone. Gives the ticket back if none could take it.
+    fn deliver(&mut self, mut issued: IssuedTicket) -> Result<(), IssuedTicket> {
+        let peer_addrs = self
+            .roads
+            .get(&issued.ticket.road)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<SocketAddr>>();
+        for peer_addr in peer_addrs {
+            let Some(sender) = self.senders.get(&peer_addr) else {
+                continue;
+            };
+            match sender.try_send(issued) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(returned)) => issued = returned,
+                Err(TrySendError::Closed(returned)) => {
+                    self.unregister(peer_addr);
+                    issued = returned;
+                }
+            }
+        }
+        Err(issued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatchers::Dispatchers;
+    use crate::dispatchers::IssuedTicket;
+    use crate::protocol::Ticket;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc::channel;
+    fn ticket(plate: &str, road: u16) -> IssuedTicket {
+        let ticket = Ticket {
+            plate: plate.to_owned(),
+            road,
+            mile1: 1234,
+            timestamp1: 1000000,
+            mile2: 1235,
+            timestamp2: 1000060,
+            speed: 6000,
+        };
+        IssuedTicket { ticket, limit: 60 }
+    }
+    fn peer_addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+    #[test]
+    fn dispatch_test() {
+        let (sender, mut receiver) = channel(8);
+        let mut dispatchers = Dispatchers::default();
+        dispatchers.register(peer_addr(5000), sender, &[368]);
+        assert!(dispatchers.dispatch(ticket("RE05BKG", 368)));
+        assert_eq!(receiver.try_recv().unwrap(), ticket("RE05BKG", 368));
+        assert!(dispatchers.pending.is_empty());
+    }
+    #[test]
+    fn dispatch_pending_test() {
+        let mut dispatchers = Dispatchers::default();
+        assert!(!dispatchers.dispatch(ticket("RE05BKG", 368)));
+        assert!(!dispatchers.dispatch(ticket("UN1X", 368)));
+        assert_eq!(dispatchers.pending[&368].len(), 2);
+        let (sender, mut receiver) = channel(8);
+        dispatchers.register(peer_addr(5000), sender, &[66, 368]);
+        assert_eq!(receiver.try_recv().unwrap(), ticket("RE05BKG", 368));
+        assert_eq!(receiver.try_recv().unwrap(), ticket("UN1X", 368));
+        assert!(dispatchers.pending.is_empty());
+    }
+    #[test]
+    fn dispatch_other_road_test() {
+        let (sender, _receiver) = channel(8);
+        let mut dispatchers = Dispatchers::default();
+        dispatchers.register(peer_addr(5000), sender, &[66]);
+        assert!(!dispatchers.dispatch(ticket("RE05BKG", 368)));
+        assert_eq!(dispatchers.pending[&368].len(), 1);
+    }
+    #[test]
+    fn dispatch_full_test() {
+        let (sender1, _receiver1) = channel(1);
+        let (sender2, _receiver2) = channel(1);
+        let mut dispatchers = Dispatchers::default();
+        dispatchers.register(peer_addr(5000), sender1, &[368]);
+        assert!(dispatchers.dispatch(ticket("RE05BKG", 368)));
+        dispatchers.register(peer_addr(5001), sender2, &[368]);
+        // Whichever dispatcher took the first ticket has no room left, so the second one goes to the other.
+        assert!(dispatchers.dispatch(ticket("UN1X", 368)));
+        assert!(!dispatchers.dispatch(ticket("AB12", 368)));
+        assert_eq!(dispatchers.pending[&368].len(), 1);
+    }
+    #[test]
+    fn unregister_with_test() {
+        let (sender1, receiver1) = channel(8);
+        let (sender2, mut receiver2) = channel(8);
+        let mut dispatchers = Dispatchers::default();
+        dispatchers.register(peer_addr(5000), sender1, &[368]);
+        assert!(dispatchers.dispatch(ticket("RE05BKG", 368)));
+        let queued = dispatchers.unregister_with(peer_addr(5000), receiver1);
+        assert_eq!(queued, [ticket("RE05BKG", 368)]);
+        assert_eq!(dispatchers.pending[&368].len(), 1);
+        dispatchers.register(peer_addr(5001), sender2, &[368]);
+        assert_eq!(receiver2.try_recv().unwrap(), ticket("RE05BKG", 368));
+    }
+}
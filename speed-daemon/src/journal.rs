@@ -0,0 +1,589 @@
+use crate::dispatchers::IssuedTicket;
+use crate::protocol::deserialize_str;
+use crate::protocol::deserialize_ticket;
+use crate::protocol::deserialize_u16;
+use crate::protocol::deserialize_u32;
+use crate::protocol::next_byte;
+use crate::protocol::serialize_str;
+use crate::protocol::serialize_ticket;
+use crate::protocol::serialize_u16;
+use crate::protocol::serialize_u32;
+use crate::protocol::ProtocolError;
+use crate::protocol::Ticket;
+use crate::protocol::TICKET_FLAG;
+use crate::tickets::day;
+use crate::tickets::Observations;
+use crate::tickets::Sighting;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::fs::File;
+use tokio::fs::OpenOptions;
+use tokio::io;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::task::spawn;
+
+/// Append-only file of the observations and tickets seen by the server, replayed on startup to recover its state.
+/// The file is written by a task of its own, in the order records are handed to it, so that callers can hand
+/// them over while holding the lock on the state they describe and wait for them to be saved once it is released.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    sender: UnboundedSender<Command>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if needed, and returns the records it holds. A record cut short
+    /// by a crash is discarded.
+    pub(crate) async fn open(path: PathBuf) -> io::Result<(Journal, Vec<Record>)> {
+        let bytes = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => Err(err)?,
+        };
+        let (records, len) = deserialize_records(&bytes);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.set_len(len as u64).await?;
+        let (sender, receiver) = unbounded_channel();
+        spawn(write_journal(JournalFile { path, file }, receiver));
+        Ok((Journal { sender }, records))
+    }
+
+    /// Appends a record, which is saved once the returned future completes.
+    pub(crate) fn append(&self, record: &Record) -> impl Future<Output = io::Result<()>> {
+        let bytes = serialize_record(record);
+        self.send(|done| Command::Append(bytes, done))
+    }
+
+    /// Rewrites the journal keeping only the observations still held in `observations`, and the tickets that may
+    /// still suppress another given them and `horizon`, before which sightings are dropped. Delivered tickets are
+    /// kept as such, without the pending record they were issued with, so that they are never dispatched again.
+    pub(crate) fn compact(
+        &self,
+        observations: &Observations,
+        horizon: Option<u32>,
+    ) -> impl Future<Output = io::Result<()>> {
+        let bytes = serialize_observations(observations);
+        let horizon = horizon.map(|horizon| TicketHorizon::new(observations, horizon));
+        self.send(|done| Command::Compact(bytes, horizon, done))
+    }
+
+    fn send(&self, command: impl FnOnce(Done) -> Command) -> impl Future<Output = io::Result<()>> {
+        let (done, result) = oneshot::channel();
+        let _ = self.sender.send(command(done));
+        async move {
+            match result.await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::other("the journal is closed")),
+            }
+        }
+    }
+}
+
+type Done = oneshot::Sender<io::Result<()>>;
+
+/// Work for the task writing the journal, along with where to report how it went.
+#[derive(Debug)]
+enum Command {
+    /// Bytes of a record to append.
+    Append(io::Result<Vec<u8>>, Done),
+    /// Bytes of the observations to keep.
+    Compact(io::Result<Vec<u8>>, Option<TicketHorizon>, Done),
+}
+
+/// First day on which each plate may still get a ticket, given the sightings held and those that may still
+/// arrive. Tickets ending before it can no longer suppress another.
+#[derive(Debug)]
+struct TicketHorizon {
+    /// First day of the sightings held of each plate, for those held since before `day`.
+    plates: HashMap<String, u32>,
+    /// Day of the horizon, before which sightings are dropped.
+    day: u32,
+}
+
+impl TicketHorizon {
+    fn new(observations: &Observations, horizon: u32) -> TicketHorizon {
+        let horizon_day = day(horizon);
+        let mut plates = HashMap::new();
+        for (_, plate, sighting) in observations.sightings() {
+            let sighting_day = day(sighting.timestamp);
+            if sighting_day < horizon_day {
+                let first_day = plates.entry(plate.to_owned()).or_insert(sighting_day);
+                *first_day = sighting_day.min(*first_day);
+            }
+        }
+        TicketHorizon {
+            plates,
+            day: horizon_day,
+        }
+    }
+
+    fn is_past(&self, ticket: &Ticket) -> bool {
+        let first_day = self.plates.get(&ticket.plate).copied();
+        day(ticket.timestamp2) < first_day.unwrap_or(self.day)
+    }
+}
+
+/// Writes the records handed to the journal until it is dropped.
+async fn write_journal(mut file: JournalFile, mut receiver: UnboundedReceiver<Command>) {
+    let mut next = receiver.recv().await;
+    while let Some(command) = next.take() {
+        match command {
+            Command::Append(bytes, done) => {
+                // Records handed over while the previous ones were written are appended along with this one.
+                let mut appends = vec![(bytes, done)];
+                while let Ok(command) = receiver.try_recv() {
+                    match command {
+                        Command::Append(bytes, done) => appends.push((bytes, done)),
+                        command => {
+                            next = Some(command);
+                            break;
+                        }
+                    }
+                }
+                append_all(&mut file, appends).await;
+            }
+            Command::Compact(observations, horizon, done) => {
+                let result = match observations {
+                    Ok(observations) => file.compact(&observations, horizon.as_ref()).await,
+                    Err(err) => Err(err),
+                };
+                let _ = done.send(result);
+            }
+        }
+        if next.is_none() {
+            next = receiver.recv().await;
+        }
+    }
+}
+
+/// Appends records together, syncing them to disk once for all of them.
+async fn append_all(file: &mut JournalFile, appends: Vec<(io::Result<Vec<u8>>, Done)>) {
+    let mut batch = Vec::new();
+    let mut waiting = Vec::new();
+    for (bytes, done) in appends {
+        match bytes {
+            Ok(bytes) => {
+                batch.extend(bytes);
+                waiting.push(done);
+            }
+            Err(err) => {
+                let _ = done.send(Err(err));
+            }
+        }
+    }
+    if waiting.is_empty() {
+        return;
+    }
+    let result = file.append(&batch).await;
+    for done in waiting {
+        let result = match &result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+        };
+        let _ = done.send(result);
+    }
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl JournalFile {
+    async fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes).await?;
+        self.file.flush().await?;
+        self.file.sync_data().await
+    }
+
+    async fn compact(
+        &mut self,
+        observations: &[u8],
+        horizon: Option<&TicketHorizon>,
+    ) -> io::Result<()> {
+        let bytes = fs::read(&self.path).await?;
+        let (records, _) = deserialize_records(&bytes);
+        let delivered = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Delivered(ticket) => Some(ticket.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<Ticket>>();
+        let mut compacted = Vec::new();
+        for record in records {
+            let record = match record {
+                Record::Observation { .. } | Record::Delivered(_) => continue,
+                Record::Pending(issued) if delivered.contains(&issued.ticket) => {
+                    Record::Ticket(issued.ticket)
+                }
+                record => record,
+            };
+            if let Record::Ticket(ticket) = &record {
+                if horizon.is_some_and(|horizon| horizon.is_past(ticket)) {
+                    continue;
+                }
+            }
+            compacted.extend(serialize_record(&record)?);
+        }
+        compacted.extend(observations);
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path).await?;
+        tmp.write_all(&compacted).await?;
+        tmp.sync_data().await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        self.file = OpenOptions::new().append(true).open(&self.path).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Record {
+    Observation {
+        road: u16,
+        plate: String,
+        sighting: Sighting,
+    },
+    /// A delivered ticket, whose days the ledger must remember.
+    Ticket(Ticket),
+    /// A ticket issued but not yet written to a dispatcher, unless a [`Record::Delivered`] follows.
+    Pending(IssuedTicket),
+    /// Marks a pending ticket as written to a dispatcher.
+    Delivered(Ticket),
+}
+
+const OBSERVATION_RECORD: u8 = 0x01;
+
+/// Followed by the limit that the pending ticket broke and the ticket, stored like other tickets.
+const PENDING_RECORD: u8 = 0x02;
+
+/// Followed by the delivered ticket, stored like other tickets.
+const DELIVERED_RECORD: u8 = 0x03;
+
+/// Tickets are stored exactly as they are sent to dispatchers.
+const TICKET_RECORD: u8 = TICKET_FLAG;
+
+fn serialize_record(record: &Record) -> io::Result<Vec<u8>> {
+    let bytes = match record {
+        Record::Observation {
+            road,
+            plate,
+            sighting,
+        } => serialize_observation(*road, plate, *sighting),
+        Record::Ticket(ticket) => serialize_ticket(ticket),
+        Record::Pending(issued) => serialize_ticket(&issued.ticket).map(|ticket| {
+            let mut bytes = vec![PENDING_RECORD];
+            bytes.extend(serialize_u16(issued.limit));
+            bytes.extend(ticket);
+            bytes
+        }),
+        Record::Delivered(ticket) => serialize_ticket(ticket).map(|ticket| {
+            let mut bytes = vec![DELIVERED_RECORD];
+            bytes.extend(ticket);
+            bytes
+        }),
+    };
+    bytes.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn serialize_observations(observations: &Observations) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for (road, plate, sighting) in observations.sightings() {
+        let record = Record::Observation {
+            road,
+            plate: plate.to_owned(),
+            sighting,
+        };
+        bytes.extend(serialize_record(&record)?);
+    }
+    Ok(bytes)
+}
+
+fn serialize_observation(
+    road: u16,
+    plate: &str,
+    sighting: Sighting,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::new();
+    bytes.push(OBSERVATION_RECORD);
+    bytes.extend(serialize_u16(road));
+    bytes.extend(serialize_u16(sighting.mile));
+    bytes.extend(serialize_str(plate)?);
+    bytes.extend(serialize_u32(sighting.timestamp));
+    Ok(bytes)
+}
+
+/// Reads records until the bytes run out or stop making sense, returning them along with the number of bytes
+/// they took.
+fn deserialize_records(bytes: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut len = 0;
+    let mut iter = bytes.iter().copied();
+    while let Ok(record) = deserialize_record(&mut iter) {
+        records.push(record);
+        len = bytes.len() - iter.len();
+    }
+    (records, len)
+}
+
+fn deserialize_record(bytes: &mut impl Iterator<Item = u8>) -> Result<Record, ProtocolError> {
+    let flag = next_byte(bytes)?;
+    match flag {
+        OBSERVATION_RECORD => deserialize_observation(bytes),
+        TICKET_RECORD => deserialize_ticket(bytes).map(Record::Ticket),
+        PENDING_RECORD => {
+            let limit = deserialize_u16(bytes)?;
+            match next_byte(bytes)? {
+                TICKET_FLAG => {
+                    let ticket = deserialize_ticket(bytes)?;
+                    Ok(Record::Pending(IssuedTicket { ticket, limit }))
+                }
+                flag => Err(ProtocolError::UnknownMessageType(flag)),
+            }
+        }
+        DELIVERED_RECORD => match next_byte(bytes)? {
+            TICKET_FLAG => deserialize_ticket(bytes).map(Record::Delivered),
+            flag => Err(ProtocolError::UnknownMessageType(flag)),
+        },
+        flag => Err(ProtocolError::UnknownMessageType(flag)),
+    }
+}
+
+fn deserialize_observation(bytes: &mut impl Iterator<Item = u8>) -> Result<Record, ProtocolError> {
+    let road = deserialize_u16(bytes)?;
+    let mile = deserialize_u16(bytes)?;
+    let plate = deserialize_str(bytes)?;
+    let timestamp = deserialize_u32(bytes)?;
+    let sighting = Sighting { mile, timestamp };
+    Ok(Record::Observation {
+        road,
+        plate,
+        sighting,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatchers::IssuedTicket;
+    use crate::journal::Journal;
+    use crate::journal::Record;
+    use crate::protocol::IAmCamera;
+    use crate::protocol::Plate;
+    use crate::protocol::Ticket;
+    use crate::test_util::temp_path;
+    use crate::tickets::Observations;
+    use crate::tickets::Sighting;
+    use std::path::PathBuf;
+    use tokio::fs;
+
+    async fn journal_path(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        let _ = fs::remove_file(&path).await;
+        path
+    }
+    #[tokio::test]
+    async fn replay_test() {
+        let path = journal_path("replay").await;
+        let (journal, records) = Journal::open(path.clone()).await.unwrap();
+        assert!(records.is_empty());
+        let observation = Record::Observation {
+            road: 123,
+            plate: "UN1X".to_owned(),
+            sighting: Sighting {
+                mile: 8,
+                timestamp: 0,
+            },
+        };
+        let ticket = Record::Ticket(Ticket {
+            plate: "UN1X".to_owned(),
+            road: 123,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        });
+        journal.append(&observation).await.unwrap();
+        journal.append(&ticket).await.unwrap();
+        drop(journal);
+        let (_, records) = Journal::open(path.clone()).await.unwrap();
+        let [Record::Observation {
+            road,
+            plate,
+            sighting,
+        }, Record::Ticket(ticket)] = &records[..]
+        else {
+            panic!("expected an observation and a ticket");
+        };
+        assert_eq!(*road, 123);
+        assert_eq!(plate, "UN1X");
+        assert_eq!(sighting.mile, 8);
+        assert_eq!(sighting.timestamp, 0);
+        assert_eq!(ticket.plate, "UN1X".to_owned());
+        assert_eq!(ticket.timestamp2, 45);
+        assert_eq!(ticket.speed, 8000);
+        fs::remove_file(&path).await.unwrap();
+    }
+    #[tokio::test]
+    async fn replay_truncated_test() {
+        let path = journal_path("truncated").await;
+        let bytes = b"\x01\x00\x7b\x00\x08\x04UN1X\x00\x00\x00\x00\x01\x00\x7b\x00\x09\x04UN";
+        fs::write(&path, bytes).await.unwrap();
+        let (journal, records) = Journal::open(path.clone()).await.unwrap();
+        assert_eq!(records.len(), 1);
+        let observation = Record::Observation {
+            road: 123,
+            plate: "UN1X".to_owned(),
+            sighting: Sighting {
+                mile: 9,
+                timestamp: 45,
+            },
+        };
+        journal.append(&observation).await.unwrap();
+        drop(journal);
+        let (_, records) = Journal::open(path.clone()).await.unwrap();
+        assert_eq!(records.len(), 2);
+        fs::remove_file(&path).await.unwrap();
+    }
+    #[tokio::test]
+    async fn compact_test() {
+        let path = journal_path("compact").await;
+        let (journal, _) = Journal::open(path.clone()).await.unwrap();
+        let mut observations = Observations::default();
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        for timestamp in [0, 45, 90] {
+            let plate = Plate {
+                plate: "UN1X".to_owned(),
+                timestamp,
+            };
+            let record = Record::Observation {
+                road: 123,
+                plate: "UN1X".to_owned(),
+                sighting: Sighting { mile: 8, timestamp },
+            };
+            journal.append(&record).await.unwrap();
+            observations.record(i_am_camera, plate);
+        }
+        let ticket = Ticket {
+            plate: "UN1X".to_owned(),
+            road: 123,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        };
+        let issued = IssuedTicket {
+            ticket: ticket.clone(),
+            limit: 60,
+        };
+        journal
+            .append(&Record::Pending(issued.clone()))
+            .await
+            .unwrap();
+        journal
+            .append(&Record::Delivered(ticket.clone()))
+            .await
+            .unwrap();
+        let other = IssuedTicket {
+            ticket: Ticket {
+                plate: "RE05BKG".to_owned(),
+                ..ticket.clone()
+            },
+            limit: 60,
+        };
+        journal
+            .append(&Record::Pending(other.clone()))
+            .await
+            .unwrap();
+        // What the store no longer holds, as after retention, is dropped from the journal too.
+        observations.retain(|_, _, sighting| sighting.timestamp == 90);
+        journal.compact(&observations, None).await.unwrap();
+        drop(journal);
+        let (_, records) = Journal::open(path.clone()).await.unwrap();
+        let [Record::Ticket(delivered), Record::Pending(pending), Record::Observation { sighting, .. }] =
+            &records[..]
+        else {
+            panic!("expected a delivered ticket, a pending ticket and an observation");
+        };
+        assert_eq!(delivered, &ticket);
+        assert_eq!(pending, &other);
+        assert_eq!(sighting.timestamp, 90);
+        fs::remove_file(&path).await.unwrap();
+    }
+    #[tokio::test]
+    async fn compact_horizon_test() {
+        let path = journal_path("compact_horizon").await;
+        let (journal, _) = Journal::open(path.clone()).await.unwrap();
+        let mut observations = Observations::default();
+        for (plate, timestamp) in [("UN1X", 90), ("AB12", 259200)] {
+            let sighting = Sighting { mile: 8, timestamp };
+            observations.restore(123, plate.to_owned(), sighting);
+        }
+        let ticket = Ticket {
+            plate: "UN1X".to_owned(),
+            road: 123,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        };
+        let old = Ticket {
+            plate: "RE05BKG".to_owned(),
+            ..ticket.clone()
+        };
+        let recent = Ticket {
+            timestamp1: 172800,
+            timestamp2: 172845,
+            ..old.clone()
+        };
+        let pending = IssuedTicket {
+            ticket: Ticket {
+                plate: "XY99".to_owned(),
+                ..ticket.clone()
+            },
+            limit: 60,
+        };
+        for record in [
+            Record::Ticket(ticket.clone()),
+            Record::Ticket(old),
+            Record::Ticket(recent.clone()),
+            Record::Pending(pending.clone()),
+        ] {
+            journal.append(&record).await.unwrap();
+        }
+        // Sightings before day 2 are dropped, so only the plates still held from before then may get a ticket on
+        // those days. Pending tickets are kept whatever their days.
+        journal.compact(&observations, Some(172800)).await.unwrap();
+        drop(journal);
+        let (_, records) = Journal::open(path.clone()).await.unwrap();
+        let tickets = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Ticket(ticket) => Some(ticket.clone()),
+                Record::Pending(issued) => Some(issued.ticket.clone()),
+                _ => None,
+            })
+            .collect::<Vec<Ticket>>();
+        assert_eq!(tickets, vec![ticket, recent, pending.ticket]);
+        assert_eq!(records.len(), 5);
+        fs::remove_file(&path).await.unwrap();
+    }
+}
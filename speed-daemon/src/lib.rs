@@ -0,0 +1,18 @@
+mod admin;
+pub mod audit;
+mod auth;
+pub mod client;
+pub mod config;
+mod dispatchers;
+mod journal;
+mod metrics;
+pub mod policy;
+pub mod protocol;
+pub mod recorder;
+mod retention;
+mod roads;
+pub mod server;
+#[cfg(test)]
+mod test_util;
+mod tickets;
+mod tls;
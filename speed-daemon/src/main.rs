@@ -1,309 +1,175 @@
-use std::{
-    io::{self, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
-    thread::{sleep, spawn},
-    time::Duration,
-};
-
-fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8080")?;
-    for stream in listener.incoming().flatten() {
-        spawn(|| handle_connection(stream));
-    }
-    Ok(())
-}
-
-fn handle_connection(stream: TcpStream) -> Option<()> {
-    let mut write_stream = stream.try_clone().ok()?;
-    let mut bytes = BufReader::new(stream).bytes().flatten();
-    let mut client = Client::default();
-    loop {
-        let Some(request) = deserialize_request(&mut bytes) else {
-            send_error_msg(&mut write_stream, "bad message type")?;
-            None?
-        };
-        match request {
-            Request::IAmCamera(i_am_camera) => {
-                if client.i_am_camera.is_none() && client.i_am_dispatcher.is_none() {
-                    client.i_am_camera = Some(i_am_camera);
-                } else {
-                    send_error_msg(&mut write_stream, "bad id")?;
-                    None?;
-                }
-            }
-            Request::IAmDispatcher(i_am_dispatcher) => {
-                if client.i_am_camera.is_none() && client.i_am_dispatcher.is_none() {
-                    client.i_am_dispatcher = Some(i_am_dispatcher);
-                    todo!()
-                } else {
-                    send_error_msg(&mut write_stream, "bad id")?;
-                    None?;
-                }
-            }
-            Request::WantHeartbeat(want_heartbeat) => {
-                if client.want_heartbeat.is_none() {
-                    client.want_heartbeat = Some(want_heartbeat);
-                    let write_stream = write_stream.try_clone().ok()?;
-                    spawn(move || handle_want_heartbeat(write_stream, want_heartbeat));
-                } else {
-                    send_error_msg(&mut write_stream, "already heartbeat")?;
-                    None?;
-                }
-            }
-            Request::Plate(plate) => {
-                let Some(i_am_camera) = client.i_am_camera else {
-                    send_error_msg(&mut write_stream, "not a camera")?;
-                    None?
-                };
-                todo!()
-            }
-        }
-    }
-}
-
-fn handle_want_heartbeat(mut stream: TcpStream, want_heartbeat: WantHeartbeat) -> io::Result<()> {
-    let interval: u64 = want_heartbeat.interval.into();
-    loop {
-        stream.write_all(&[HEARTBEAT_FLAG])?;
-        sleep(Duration::from_millis(interval * 100));
-    }
-}
-
-fn send_error_msg(stream: &mut TcpStream, msg: &str) -> Option<()> {
-    let bytes = serialize_error_msg(msg)?;
-    stream.write_all(&bytes).ok()
-}
-
-#[derive(Default, Debug, Clone)]
-struct Client {
-    i_am_camera: Option<IAmCamera>,
-    i_am_dispatcher: Option<IAmDispatcher>,
-    want_heartbeat: Option<WantHeartbeat>,
-}
-
-#[derive(Debug, Clone)]
-struct Plate {
-    plate: String,
-    timestamp: u32,
-}
-
-#[derive(Debug, Clone)]
-struct Ticket {
-    plate: String,
-    road: u16,
-    mile1: u16,
-    timestamp1: u32,
-    mile2: u16,
-    timestamp2: u32,
-    speed: u16,
-}
-
-#[derive(Debug, Clone, Copy)]
-struct WantHeartbeat {
-    interval: u32,
-}
-
-#[derive(Debug, Clone, Copy)]
-struct IAmCamera {
-    road: u16,
-    mile: u16,
-    limit: u16,
-}
-
-#[derive(Debug, Clone)]
-struct IAmDispatcher {
-    roads: Vec<u16>,
-}
-
-enum Request {
-    IAmCamera(IAmCamera),
-    IAmDispatcher(IAmDispatcher),
-    WantHeartbeat(WantHeartbeat),
-    Plate(Plate),
-}
-
-const ERROR_FLAG: u8 = 0x10;
-
-const PLATE_FLAG: u8 = 0x20;
-
-const TICKET_FLAG: u8 = 0x21;
-
-const WANT_HEARTBEAT_FLAG: u8 = 0x40;
-
-const HEARTBEAT_FLAG: u8 = 0x41;
-
-const I_AM_CAMERA_FLAG: u8 = 0x80;
-
-const I_AM_DISPATCHER_FLAG: u8 = 0x81;
-
-fn deserialize_request(bytes: &mut impl Iterator<Item = u8>) -> Option<Request> {
-    let flag = bytes.next()?;
-    match flag {
-        I_AM_CAMERA_FLAG => deserialize_i_am_camera(bytes).map(Request::IAmCamera),
-        I_AM_DISPATCHER_FLAG => deserialize_i_am_dispatcher(bytes).map(Request::IAmDispatcher),
-        WANT_HEARTBEAT_FLAG => deserialize_want_heartbeat(bytes).map(Request::WantHeartbeat),
-        PLATE_FLAG => deserialize_plate(bytes).map(Request::Plate),
-        _ => None,
-    }
-}
-
-fn serialize_error_msg(msg: &str) -> Option<Vec<u8>> {
-    let mut bytes = Vec::new();
-    bytes.push(ERROR_FLAG);
-    bytes.extend(serialize_str(msg)?);
-    Some(bytes)
-}
-
-fn deserialize_plate(bytes: &mut impl Iterator<Item = u8>) -> Option<Plate> {
-    let plate = Plate {
-        plate: deserialize_str(bytes)?,
-        timestamp: deserialize_u32(bytes)?,
+use clap::Parser;
+use speed_daemon::config::AuditFormat;
+use speed_daemon::config::Config;
+use speed_daemon::config::LogFormat;
+use speed_daemon::config::TlsConfig;
+use speed_daemon::server::Server;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tokio::task::spawn;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+/// Speed limit enforcement server. Options given on the command line take precedence over the config file.
+#[derive(Parser, Debug)]
+struct Args {
+    /// TOML file with the settings of the server.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+    #[arg(long)]
+    max_connections: Option<NonZeroUsize>,
+    #[arg(long)]
+    max_plate_len: Option<u8>,
+    /// Hundredths of miles per hour over the limit before a ticket is issued.
+    #[arg(long)]
+    speed_tolerance: Option<u16>,
+    /// Percent over the limit before a ticket is issued, in place of the speed tolerance.
+    #[arg(long)]
+    percent_tolerance: Option<u16>,
+    /// Fewest miles between two sightings for a ticket.
+    #[arg(long)]
+    min_distance: Option<u16>,
+    /// Most seconds between two sightings for a ticket.
+    #[arg(long)]
+    max_duration: Option<u32>,
+    /// Deciseconds.
+    #[arg(long)]
+    min_heartbeat_interval: Option<u32>,
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// Seconds before the latest sighting within which sightings are kept.
+    #[arg(long)]
+    retention_window: Option<u32>,
+    /// Most sightings held in memory.
+    #[arg(long)]
+    max_observations: Option<usize>,
+    /// Address of the admin HTTP endpoint, which serves the live state at `/state` and metrics at `/metrics`.
+    #[arg(long)]
+    admin: Option<SocketAddr>,
+    /// File to which every ticket emitted is appended.
+    #[arg(long)]
+    audit: Option<PathBuf>,
+    #[arg(long)]
+    audit_format: Option<AuditFormat>,
+    /// Directory in which the bytes received on each connection are saved, for the replay tool, with camera
+    /// tokens redacted.
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Address on which cameras and dispatchers connect over TLS.
+    #[arg(long)]
+    tls_listen: Option<SocketAddr>,
+    /// PEM file of the certificate chain of the TLS listener.
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of the TLS listener.
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// TOML file of the tokens that cameras must present before identifying.
+    #[arg(long)]
+    tokens: Option<PathBuf>,
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).await?,
+        None => Config::default(),
     };
-    Some(plate)
-}
-
-fn serialize_ticket(ticket: &Ticket) -> Option<Vec<u8>> {
-    let mut bytes = Vec::new();
-    bytes.push(TICKET_FLAG);
-    bytes.extend(serialize_str(&ticket.plate)?);
-    bytes.extend(serialize_u16(ticket.road));
-    bytes.extend(serialize_u16(ticket.mile1));
-    bytes.extend(serialize_u32(ticket.timestamp1));
-    bytes.extend(serialize_u16(ticket.mile2));
-    bytes.extend(serialize_u32(ticket.timestamp2));
-    bytes.extend(serialize_u16(ticket.speed));
-    Some(bytes)
-}
-
-fn deserialize_want_heartbeat(bytes: &mut impl Iterator<Item = u8>) -> Option<WantHeartbeat> {
-    let want_heartbeat = WantHeartbeat {
-        interval: deserialize_u32(bytes)?,
+    config.listen = args.listen.unwrap_or(config.listen);
+    config.max_connections = args.max_connections.unwrap_or(config.max_connections);
+    config.max_plate_len = args.max_plate_len.unwrap_or(config.max_plate_len);
+    config.speed_tolerance = args.speed_tolerance.unwrap_or(config.speed_tolerance);
+    config.percent_tolerance = args.percent_tolerance.or(config.percent_tolerance);
+    config.min_distance = args.min_distance.or(config.min_distance);
+    config.max_duration = args.max_duration.or(config.max_duration);
+    config.min_heartbeat_interval = args
+        .min_heartbeat_interval
+        .unwrap_or(config.min_heartbeat_interval);
+    config.journal = args.journal.or(config.journal);
+    config.retention_window = args.retention_window.or(config.retention_window);
+    config.max_observations = args.max_observations.or(config.max_observations);
+    config.admin = args.admin.or(config.admin);
+    config.audit = args.audit.or(config.audit);
+    config.audit_format = args.audit_format.unwrap_or(config.audit_format);
+    config.record = args.record.or(config.record);
+    config.tokens = args.tokens.or(config.tokens);
+    config.log_format = args.log_format.unwrap_or(config.log_format);
+    config.tls = match (config.tls, args.tls_listen, args.tls_cert, args.tls_key) {
+        (Some(tls), listen, cert, key) => Some(TlsConfig {
+            listen: listen.unwrap_or(tls.listen),
+            cert: cert.unwrap_or(tls.cert),
+            key: key.unwrap_or(tls.key),
+        }),
+        (None, Some(listen), Some(cert), Some(key)) => Some(TlsConfig { listen, cert, key }),
+        (None, None, None, None) => None,
+        (None, ..) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--tls-listen, --tls-cert and --tls-key are needed together",
+        ))?,
     };
-    Some(want_heartbeat)
-}
-
-fn deserialize_i_am_camera(bytes: &mut impl Iterator<Item = u8>) -> Option<IAmCamera> {
-    let i_am_camera = IAmCamera {
-        road: deserialize_u16(bytes)?,
-        mile: deserialize_u16(bytes)?,
-        limit: deserialize_u16(bytes)?,
+    init_logs(config.log_format);
+    let listener = TcpListener::bind(config.listen).await?;
+    let admin_listener = match config.admin {
+        Some(admin) => Some(TcpListener::bind(admin).await?),
+        None => None,
     };
-    Some(i_am_camera)
-}
-
-fn deserialize_i_am_dispatcher(bytes: &mut impl Iterator<Item = u8>) -> Option<IAmDispatcher> {
-    let i_am_dispatcher = IAmDispatcher {
-        roads: deserialize_vec(bytes)?,
+    let tls_listener = match &config.tls {
+        Some(tls) => Some(TcpListener::bind(tls.listen).await?),
+        None => None,
     };
-    Some(i_am_dispatcher)
-}
-
-fn serialize_u16(u16: u16) -> Vec<u8> {
-    u16.to_be_bytes().to_vec()
-}
-
-fn deserialize_u16(bytes: &mut impl Iterator<Item = u8>) -> Option<u16> {
-    let bytes = [bytes.next()?, bytes.next()?];
-    let u16 = u16::from_be_bytes(bytes);
-    Some(u16)
-}
-
-fn serialize_u32(u32: u32) -> Vec<u8> {
-    u32.to_be_bytes().to_vec()
-}
-
-fn deserialize_u32(bytes: &mut impl Iterator<Item = u8>) -> Option<u32> {
-    let bytes = [bytes.next()?, bytes.next()?, bytes.next()?, bytes.next()?];
-    let u32 = u32::from_be_bytes(bytes);
-    Some(u32)
-}
-
-fn serialize_str(str: &str) -> Option<Vec<u8>> {
-    let len = str.len().try_into().ok()?;
-    if !str.is_ascii() {
-        None?;
+    let server = Server::new(config).await?;
+    if let Some(admin_listener) = admin_listener {
+        spawn(server.clone().serve_admin(admin_listener));
     }
-    let mut bytes = Vec::new();
-    bytes.push(len);
-    bytes.extend_from_slice(str.as_bytes());
-    Some(bytes)
-}
-
-fn deserialize_str(bytes: &mut impl Iterator<Item = u8>) -> Option<String> {
-    let len = bytes.next()?.into();
-    let bytes = bytes.take(len).collect::<Vec<u8>>();
-    if bytes.len() != len {
-        None?;
+    if let Some(tls_listener) = tls_listener {
+        spawn(server.clone().serve_tls(tls_listener));
     }
-    if !bytes.is_ascii() {
-        None?;
+    select! {
+        result = server.clone().serve(listener) => result,
+        result = shutdown_signal() => {
+            result?;
+            let summary = server.shutdown().await?;
+            info!(
+                connections = summary.connections,
+                abandoned = summary.abandoned,
+                pending_tickets = summary.pending_tickets,
+                saved = summary.saved,
+                "shut down"
+            );
+            Ok(())
+        }
     }
-    String::from_utf8(bytes).ok()
 }
 
-fn deserialize_vec(bytes: &mut impl Iterator<Item = u8>) -> Option<Vec<u16>> {
-    let len = bytes.next()?;
-    (0..len)
-        .map(|_| deserialize_u16(bytes))
-        .collect::<Option<Vec<u16>>>()
+/// Writes logs to standard error, at the level set by `RUST_LOG` or else at the info level.
+fn init_logs(log_format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::deserialize_i_am_camera;
-    use crate::deserialize_i_am_dispatcher;
-    use crate::deserialize_plate;
-    use crate::deserialize_want_heartbeat;
-    use crate::serialize_error_msg;
-    use crate::serialize_ticket;
-    use crate::Ticket;
-    #[test]
-    fn serialize_error_msg_test() {
-        let error_msg = "bad";
-        let bytes = b"\x10\x03\x62\x61\x64";
-        assert_eq!(serialize_error_msg(error_msg).unwrap(), bytes.to_vec());
-    }
-    #[test]
-    fn deserialize_plate_test() {
-        let mut bytes = b"\x07\x52\x45\x30\x35\x42\x4b\x47\x00\x01\xe2\x40"
-            .to_vec()
-            .into_iter();
-        let plate = deserialize_plate(&mut bytes).unwrap();
-        assert_eq!(plate.plate, "RE05BKG".to_owned());
-        assert_eq!(plate.timestamp, 123456);
-    }
-    #[test]
-    fn serialize_ticket_test() {
-        let ticket = Ticket {
-            plate: "RE05BKG".to_owned(),
-            road: 368,
-            mile1: 1234,
-            timestamp1: 1000000,
-            mile2: 1235,
-            timestamp2: 1000060,
-            speed: 6000,
-        };
-        let bytes = b"\x21\x07\x52\x45\x30\x35\x42\x4b\x47\x01\x70\x04\xd2\x00\x0f\x42\x40\x04\xd3\x00\x0f\x42\x7c\x17\x70";
-        assert_eq!(serialize_ticket(&ticket).unwrap(), bytes.to_vec());
-    }
-    #[test]
-    fn deserialize_want_heartbeat_test() {
-        let mut bytes = b"\x00\x00\x04\xdb".to_vec().into_iter();
-        let want_heartbeat = deserialize_want_heartbeat(&mut bytes).unwrap();
-        assert_eq!(want_heartbeat.interval, 1243);
-    }
-    #[test]
-    fn deserialize_i_am_camera_test() {
-        let mut bytes = b"\x01\x70\x04\xd2\x00\x28".to_vec().into_iter();
-        let i_am_camera = deserialize_i_am_camera(&mut bytes).unwrap();
-        assert_eq!(i_am_camera.road, 368);
-        assert_eq!(i_am_camera.mile, 1234);
-        assert_eq!(i_am_camera.limit, 40);
-    }
-    #[test]
-    fn deserialize_i_am_dispatcher_test() {
-        let mut bytes = b"\x03\x00\x42\x01\x70\x13\x88".to_vec().into_iter();
-        let i_am_dispatcher = deserialize_i_am_dispatcher(&mut bytes).unwrap();
-        assert_eq!(i_am_dispatcher.roads, vec![66, 368, 5000]);
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        result = ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
     }
 }
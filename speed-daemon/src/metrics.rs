@@ -0,0 +1,194 @@
+use crate::protocol::ProtocolError;
+use crate::protocol::Request;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// Counters of what the server did since it started, exported in the Prometheus text format.
+#[derive(Default, Debug)]
+pub(crate) struct Metrics {
+    messages_decoded: Mutex<BTreeMap<&'static str, u64>>,
+    protocol_errors: Mutex<BTreeMap<&'static str, u64>>,
+    tickets_generated: AtomicU64,
+    tickets_delivered: AtomicU64,
+    tickets_suppressed: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    audit_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn message_decoded(&self, request: &Request) {
+        let message_type = match request {
+            Request::Authenticate(_) => "authenticate",
+            Request::Plate(_) => "plate",
+            Request::WantHeartbeat(_) => "want_heartbeat",
+            Request::IAmCamera(_) => "i_am_camera",
+            Request::IAmDispatcher(_) => "i_am_dispatcher",
+        };
+        increment(&self.messages_decoded, message_type);
+    }
+
+    pub(crate) fn protocol_error(&self, err: &ProtocolError) {
+        let kind = match err {
+            ProtocolError::Io(_) => "io",
+            ProtocolError::UnknownMessageType(_) => "unknown_message_type",
+            ProtocolError::Truncated => "truncated",
+            ProtocolError::NonAsciiString => "non_ascii_string",
+            ProtocolError::StringTooLong(_) => "string_too_long",
+            ProtocolError::ListTooLong(_) => "list_too_long",
+            ProtocolError::DuplicateIdentity => "duplicate_identity",
+            ProtocolError::DuplicateHeartbeat => "duplicate_heartbeat",
+            ProtocolError::NotACamera => "not_a_camera",
+            ProtocolError::DuplicateToken => "duplicate_token",
+            ProtocolError::Unauthorized { .. } => "unauthorized",
+            ProtocolError::InconsistentLimit { .. } => "inconsistent_limit",
+            ProtocolError::DuplicateMile { .. } => "duplicate_mile",
+            ProtocolError::ShuttingDown => "shutting_down",
+            ProtocolError::Rejected(_) => "rejected",
+        };
+        increment(&self.protocol_errors, kind);
+    }
+
+    pub(crate) fn ticket_generated(&self) {
+        self.tickets_generated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn tickets_delivered(&self, count: usize) {
+        self.tickets_delivered
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a ticket that was not issued because the car already got one on one of its days.
+    pub(crate) fn ticket_suppressed(&self) {
+        self.tickets_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn heartbeat_sent(&self) {
+        self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn audit_error(&self) {
+        self.audit_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Formats every metric in the Prometheus text exposition format, along with the current number of tickets
+    /// waiting for a dispatcher.
+    pub(crate) fn render(&self, pending_tickets: usize) -> String {
+        let mut text = String::new();
+        let messages_decoded = self.messages_decoded.lock().unwrap();
+        write_header(
+            &mut text,
+            "speed_daemon_messages_decoded_total",
+            "counter",
+            "Messages decoded from clients, by type.",
+        );
+        for (message_type, count) in messages_decoded.iter() {
+            let _ = writeln!(
+                text,
+                "speed_daemon_messages_decoded_total{{type=\"{message_type}\"}} {count}"
+            );
+        }
+        drop(messages_decoded);
+        let protocol_errors = self.protocol_errors.lock().unwrap();
+        write_header(
+            &mut text,
+            "speed_daemon_protocol_errors_total",
+            "counter",
+            "Connections closed because of a protocol error, by kind.",
+        );
+        for (kind, count) in protocol_errors.iter() {
+            let _ = writeln!(
+                text,
+                "speed_daemon_protocol_errors_total{{kind=\"{kind}\"}} {count}"
+            );
+        }
+        drop(protocol_errors);
+        let counters = [
+            (
+                "speed_daemon_tickets_generated_total",
+                "Tickets produced by speeding cars, before the one per day rule.",
+                &self.tickets_generated,
+            ),
+            (
+                "speed_daemon_tickets_delivered_total",
+                "Tickets sent to a dispatcher.",
+                &self.tickets_delivered,
+            ),
+            (
+                "speed_daemon_tickets_suppressed_total",
+                "Tickets dropped because the car was already ticketed on the same day.",
+                &self.tickets_suppressed,
+            ),
+            (
+                "speed_daemon_heartbeats_sent_total",
+                "Heartbeats written to clients.",
+                &self.heartbeats_sent,
+            ),
+            (
+                "speed_daemon_audit_errors_total",
+                "Audit entries that could not be written.",
+                &self.audit_errors,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            write_header(&mut text, name, "counter", help);
+            let _ = writeln!(text, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+        write_header(
+            &mut text,
+            "speed_daemon_pending_tickets",
+            "gauge",
+            "Tickets waiting for a dispatcher of their road.",
+        );
+        let _ = writeln!(text, "speed_daemon_pending_tickets {pending_tickets}");
+        text
+    }
+}
+
+fn increment(counters: &Mutex<BTreeMap<&'static str, u64>>, label: &'static str) {
+    *counters.lock().unwrap().entry(label).or_default() += 1;
+}
+
+fn write_header(text: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {metric_type}");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+    use crate::protocol::Plate;
+    use crate::protocol::ProtocolError;
+    use crate::protocol::Request;
+    #[test]
+    fn render_test() {
+        let metrics = Metrics::default();
+        let plate = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        metrics.message_decoded(&Request::Plate(plate.clone()));
+        metrics.message_decoded(&Request::Plate(plate));
+        metrics.protocol_error(&ProtocolError::NotACamera);
+        metrics.ticket_generated();
+        metrics.ticket_generated();
+        metrics.ticket_suppressed();
+        metrics.tickets_delivered(1);
+        metrics.heartbeat_sent();
+        metrics.audit_error();
+        let text = metrics.render(3);
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert!(lines.contains(&"# TYPE speed_daemon_messages_decoded_total counter"));
+        assert!(lines.contains(&"speed_daemon_messages_decoded_total{type=\"plate\"} 2"));
+        assert!(lines.contains(&"speed_daemon_protocol_errors_total{kind=\"not_a_camera\"} 1"));
+        assert!(lines.contains(&"speed_daemon_tickets_generated_total 2"));
+        assert!(lines.contains(&"speed_daemon_tickets_delivered_total 1"));
+        assert!(lines.contains(&"speed_daemon_tickets_suppressed_total 1"));
+        assert!(lines.contains(&"speed_daemon_heartbeats_sent_total 1"));
+        assert!(lines.contains(&"speed_daemon_audit_errors_total 1"));
+        assert!(lines.contains(&"# TYPE speed_daemon_pending_tickets gauge"));
+        assert!(lines.contains(&"speed_daemon_pending_tickets 3"));
+    }
+}
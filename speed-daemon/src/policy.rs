@@ -0,0 +1,246 @@
+use crate::config::Config;
+use crate::tickets::SPEED_TOLERANCE;
+use serde::Deserialize;
+use std::fmt::Debug;
+
+/// Trip of a car between two neighbouring sightings on a road, the earlier one first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Journey {
+    pub road: u16,
+    /// Limit of the road, in miles per hour.
+    pub limit: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    /// Always after `timestamp1`.
+    pub timestamp2: u32,
+    /// Average speed, in hundredths of miles per hour.
+    pub speed: u64,
+}
+
+impl Journey {
+    pub fn distance(&self) -> u16 {
+        self.mile1.abs_diff(self.mile2)
+    }
+
+    pub fn duration(&self) -> u32 {
+        self.timestamp2 - self.timestamp1
+    }
+}
+
+/// Rule deciding which journeys get a ticket, consulted for each pair of neighbouring sightings.
+pub trait ViolationPolicy: Debug + Send + Sync {
+    fn is_violation(&self, journey: &Journey) -> bool;
+}
+
+/// Rule of the protocol: a ticket when the average speed is at least this many hundredths of miles per hour over
+/// the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpeedTolerance(pub u16);
+
+impl Default for SpeedTolerance {
+    fn default() -> Self {
+        SpeedTolerance(SPEED_TOLERANCE)
+    }
+}
+
+impl ViolationPolicy for SpeedTolerance {
+    fn is_violation(&self, journey: &Journey) -> bool {
+        journey.speed >= u64::from(journey.limit) * 100 + u64::from(self.0)
+    }
+}
+
+/// A ticket when the average speed is at least this many percent over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PercentTolerance(pub u16);
+
+impl ViolationPolicy for PercentTolerance {
+    fn is_violation(&self, journey: &Journey) -> bool {
+        journey.speed >= u64::from(journey.limit) * (100 + u64::from(self.0))
+    }
+}
+
+/// Ignores journeys shorter than `miles`, over which the average speed is too imprecise.
+#[derive(Debug)]
+pub struct MinDistance {
+    pub miles: u16,
+    pub inner: Box<dyn ViolationPolicy>,
+}
+
+impl ViolationPolicy for MinDistance {
+    fn is_violation(&self, journey: &Journey) -> bool {
+        journey.distance() >= self.miles && self.inner.is_violation(journey)
+    }
+}
+
+/// Ignores journeys taking longer than `seconds`, whose sightings may belong to separate trips.
+#[derive(Debug)]
+pub struct MaxDuration {
+    pub seconds: u32,
+    pub inner: Box<dyn ViolationPolicy>,
+}
+
+impl ViolationPolicy for MaxDuration {
+    fn is_violation(&self, journey: &Journey) -> bool {
+        journey.duration() <= self.seconds && self.inner.is_violation(journey)
+    }
+}
+
+/// Limit in force during part of every day, in place of the limit given by the cameras.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeOfDayLimit {
+    /// Seconds after midnight at which the limit starts.
+    pub start: u32,
+    /// Seconds after midnight at which the limit ends. A period ending before it starts runs past midnight.
+    pub end: u32,
+    pub limit: u16,
+}
+
+impl TimeOfDayLimit {
+    fn contains(&self, timestamp: u32) -> bool {
+        let time = timestamp % 86400;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&time)
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Judges journeys against the limit in force when they started, which is the first of `limits` containing that
+/// time, or the limit of the cameras.
+#[derive(Debug)]
+pub struct TimeOfDay {
+    pub limits: Vec<TimeOfDayLimit>,
+    pub inner: Box<dyn ViolationPolicy>,
+}
+
+impl ViolationPolicy for TimeOfDay {
+    fn is_violation(&self, journey: &Journey) -> bool {
+        let limit = self
+            .limits
+            .iter()
+            .find(|limit| limit.contains(journey.timestamp1))
+            .map_or(journey.limit, |limit| limit.limit);
+        let journey = Journey { limit, ..*journey };
+        self.inner.is_violation(&journey)
+    }
+}
+
+/// Builds the policy described by the settings of a server.
+pub(crate) fn from_config(config: &Config) -> Box<dyn ViolationPolicy> {
+    let mut policy: Box<dyn ViolationPolicy> = match config.percent_tolerance {
+        Some(percent) => Box::new(PercentTolerance(percent)),
+        None => Box::new(SpeedTolerance(config.speed_tolerance)),
+    };
+    if !config.time_of_day_limits.is_empty() {
+        policy = Box::new(TimeOfDay {
+            limits: config.time_of_day_limits.clone(),
+            inner: policy,
+        });
+    }
+    if let Some(miles) = config.min_distance {
+        policy = Box::new(MinDistance {
+            miles,
+            inner: policy,
+        });
+    }
+    if let Some(seconds) = config.max_duration {
+        policy = Box::new(MaxDuration {
+            seconds,
+            inner: policy,
+        });
+    }
+    policy
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::Journey;
+    use crate::policy::MaxDuration;
+    use crate::policy::MinDistance;
+    use crate::policy::PercentTolerance;
+    use crate::policy::SpeedTolerance;
+    use crate::policy::TimeOfDay;
+    use crate::policy::TimeOfDayLimit;
+    use crate::policy::ViolationPolicy;
+    fn journey(distance: u16, duration: u32, speed: u64) -> Journey {
+        Journey {
+            road: 123,
+            limit: 60,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 8 + distance,
+            timestamp2: duration,
+            speed,
+        }
+    }
+    #[test]
+    fn speed_tolerance_test() {
+        let policy = SpeedTolerance::default();
+        assert!(policy.is_violation(&journey(1, 59, 6050)));
+        assert!(!policy.is_violation(&journey(1, 59, 6049)));
+    }
+    #[test]
+    fn percent_tolerance_test() {
+        let policy = PercentTolerance(10);
+        assert!(policy.is_violation(&journey(1, 54, 6600)));
+        assert!(!policy.is_violation(&journey(1, 55, 6599)));
+    }
+    #[test]
+    fn min_distance_test() {
+        let policy = MinDistance {
+            miles: 2,
+            inner: Box::new(SpeedTolerance::default()),
+        };
+        assert!(!policy.is_violation(&journey(1, 45, 8000)));
+        assert!(policy.is_violation(&journey(2, 90, 8000)));
+        assert!(!policy.is_violation(&journey(2, 120, 6000)));
+    }
+    #[test]
+    fn max_duration_test() {
+        let policy = MaxDuration {
+            seconds: 3600,
+            inner: Box::new(SpeedTolerance::default()),
+        };
+        assert!(policy.is_violation(&journey(80, 3600, 8000)));
+        assert!(!policy.is_violation(&journey(80, 3601, 7997)));
+    }
+    #[test]
+    fn time_of_day_test() {
+        let night = TimeOfDayLimit {
+            start: 22 * 3600,
+            end: 6 * 3600,
+            limit: 40,
+        };
+        let policy = TimeOfDay {
+            limits: vec![night],
+            inner: Box::new(SpeedTolerance::default()),
+        };
+        let day = Journey {
+            timestamp1: 12 * 3600,
+            timestamp2: 12 * 3600 + 72,
+            ..journey(1, 72, 5000)
+        };
+        assert!(!policy.is_violation(&day));
+        let late = Journey {
+            timestamp1: 86400 + 23 * 3600,
+            timestamp2: 86400 + 23 * 3600 + 72,
+            ..day
+        };
+        assert!(policy.is_violation(&late));
+        let early = Journey {
+            timestamp1: 86400 + 3600,
+            timestamp2: 86400 + 3600 + 72,
+            ..day
+        };
+        assert!(policy.is_violation(&early));
+        let morning = Journey {
+            timestamp1: 6 * 3600,
+            timestamp2: 6 * 3600 + 72,
+            ..day
+        };
+        assert!(!policy.is_violation(&morning));
+    }
+}
@@ -0,0 +1,965 @@
+use bytes::BufMut;
+use bytes::BytesMut;
+use serde::Serialize;
+use std::fmt;
+use tokio::io;
+use tokio_util::codec::Decoder;
+use tokio_util::codec::Encoder;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plate {
+    pub plate: String,
+    pub timestamp: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    pub speed: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WantHeartbeat {
+    pub interval: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct IAmCamera {
+    pub road: u16,
+    pub mile: u16,
+    pub limit: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IAmDispatcher {
+    pub roads: Vec<u16>,
+}
+
+/// Extension message in which a camera presents its token before identifying itself, when the server requires
+/// cameras to authenticate.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Authenticate {
+    pub token: String,
+}
+
+/// Leaves the token out, so that it does not end up in logs.
+impl fmt::Debug for Authenticate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Authenticate").finish_non_exhaustive()
+    }
+}
+
+/// Messages sent from clients to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Authenticate(Authenticate),
+    IAmCamera(IAmCamera),
+    IAmDispatcher(IAmDispatcher),
+    WantHeartbeat(WantHeartbeat),
+    Plate(Plate),
+}
+
+/// Messages sent from the server to clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Error(String),
+    Ticket(Ticket),
+    Heartbeat,
+}
+
+pub const ERROR_FLAG: u8 = 0x10;
+
+pub const PLATE_FLAG: u8 = 0x20;
+
+pub const TICKET_FLAG: u8 = 0x21;
+
+pub const WANT_HEARTBEAT_FLAG: u8 = 0x40;
+
+pub const HEARTBEAT_FLAG: u8 = 0x41;
+
+pub const I_AM_CAMERA_FLAG: u8 = 0x80;
+
+pub const I_AM_DISPATCHER_FLAG: u8 = 0x81;
+
+pub const AUTHENTICATE_FLAG: u8 = 0x82;
+
+/// Frames requests coming from clients and responses going to them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerCodec;
+
+impl Decoder for ServerCodec {
+    type Item = Request;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Request>, ProtocolError> {
+        let Some(len) = request_len(src)? else {
+            return Ok(None);
+        };
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(len);
+        let request = deserialize_request(&mut frame.into_iter())?;
+        Ok(Some(request))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Request>, ProtocolError> {
+        match self.decode(src)? {
+            Some(request) => Ok(Some(request)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProtocolError::Truncated),
+        }
+    }
+}
+
+impl Encoder<Response> for ServerCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, response: Response, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        let bytes = serialize_response(&response)?;
+        dst.put_slice(&bytes);
+        Ok(())
+    }
+}
+
+/// Frames requests going to the server and responses coming from it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientCodec;
+
+impl Decoder for ClientCodec {
+    type Item = Response;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Response>, ProtocolError> {
+        let Some(len) = response_len(src)? else {
+            return Ok(None);
+        };
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(len);
+        let response = deserialize_response(&mut frame.into_iter())?;
+        Ok(Some(response))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Response>, ProtocolError> {
+        match self.decode(src)? {
+            Some(response) => Ok(Some(response)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProtocolError::Truncated),
+        }
+    }
+}
+
+impl Encoder<Request> for ClientCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, request: Request, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        let bytes = serialize_request(&request)?;
+        dst.put_slice(&bytes);
+        Ok(())
+    }
+}
+
+/// Ways in which a connection can break the protocol. The description of each one is what gets sent to
+/// the client in an error message.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    UnknownMessageType(u8),
+    Truncated,
+    NonAsciiString,
+    StringTooLong(usize),
+    ListTooLong(usize),
+    DuplicateIdentity,
+    DuplicateHeartbeat,
+    NotACamera,
+    /// A client sent a second token.
+    DuplicateToken,
+    /// A camera did not present a token allowing its road and mile.
+    Unauthorized {
+        road: u16,
+        mile: u16,
+    },
+    /// A camera gave a different limit than the cameras already on its road.
+    InconsistentLimit {
+        road: u16,
+        limit: u16,
+        expected: u16,
+    },
+    /// A camera claimed a mile already covered by another camera of its road.
+    DuplicateMile {
+        road: u16,
+        mile: u16,
+    },
+    /// The server is shutting down and closes every connection.
+    ShuttingDown,
+    Rejected(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "{err}"),
+            ProtocolError::UnknownMessageType(flag) => {
+                write!(f, "unknown message type {flag:#04x}")
+            }
+            ProtocolError::Truncated => write!(f, "truncated message"),
+            ProtocolError::NonAsciiString => write!(f, "non-ascii string"),
+            ProtocolError::StringTooLong(len) => write!(f, "string of {len} bytes is too long"),
+            ProtocolError::ListTooLong(len) => write!(f, "list of {len} items is too long"),
+            ProtocolError::DuplicateIdentity => write!(f, "client already identified"),
+            ProtocolError::DuplicateHeartbeat => write!(f, "heartbeat already requested"),
+            ProtocolError::NotACamera => write!(f, "not a camera"),
+            ProtocolError::DuplicateToken => write!(f, "token already sent"),
+            ProtocolError::Unauthorized { road, mile } => {
+                write!(f, "not authorized for mile {mile} of road {road}")
+            }
+            ProtocolError::InconsistentLimit {
+                road,
+                limit,
+                expected,
+            } => write!(
+                f,
+                "limit {limit} differs from limit {expected} of road {road}"
+            ),
+            ProtocolError::DuplicateMile { road, mile } => {
+                write!(f, "road {road} already has a camera at mile {mile}")
+            }
+            ProtocolError::ShuttingDown => write!(f, "server shutting down"),
+            ProtocolError::Rejected(msg) => write!(f, "rejected by server: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
+
+/// Returns the length of the request at the start of `bytes`, or `None` if not enough bytes arrived to know it.
+pub(crate) fn request_len(bytes: &[u8]) -> Result<Option<usize>, ProtocolError> {
+    let Some(&flag) = bytes.first() else {
+        return Ok(None);
+    };
+    let len = match flag {
+        I_AM_CAMERA_FLAG => 7,
+        WANT_HEARTBEAT_FLAG => 5,
+        AUTHENTICATE_FLAG => {
+            let Some(&len) = bytes.get(1) else {
+                return Ok(None);
+            };
+            2 + usize::from(len)
+        }
+        PLATE_FLAG => {
+            let Some(&len) = bytes.get(1) else {
+                return Ok(None);
+            };
+            2 + usize::from(len) + 4
+        }
+        I_AM_DISPATCHER_FLAG => {
+            let Some(&len) = bytes.get(1) else {
+                return Ok(None);
+            };
+            2 + usize::from(len) * 2
+        }
+        flag => Err(ProtocolError::UnknownMessageType(flag))?,
+    };
+    Ok(Some(len))
+}
+
+/// Returns the length of the response at the start of `bytes`, or `None` if not enough bytes arrived to know it.
+fn response_len(bytes: &[u8]) -> Result<Option<usize>, ProtocolError> {
+    let Some(&flag) = bytes.first() else {
+        return Ok(None);
+    };
+    let len = match flag {
+        HEARTBEAT_FLAG => 1,
+        ERROR_FLAG => {
+            let Some(&len) = bytes.get(1) else {
+                return Ok(None);
+            };
+            2 + usize::from(len)
+        }
+        TICKET_FLAG => {
+            let Some(&len) = bytes.get(1) else {
+                return Ok(None);
+            };
+            2 + usize::from(len) + 16
+        }
+        flag => Err(ProtocolError::UnknownMessageType(flag))?,
+    };
+    Ok(Some(len))
+}
+
+pub fn serialize_request(request: &Request) -> Result<Vec<u8>, ProtocolError> {
+    match request {
+        Request::Authenticate(authenticate) => serialize_authenticate(authenticate),
+        Request::IAmCamera(i_am_camera) => Ok(serialize_i_am_camera(i_am_camera)),
+        Request::IAmDispatcher(i_am_dispatcher) => serialize_i_am_dispatcher(i_am_dispatcher),
+        Request::WantHeartbeat(want_heartbeat) => Ok(serialize_want_heartbeat(want_heartbeat)),
+        Request::Plate(plate) => serialize_plate(plate),
+    }
+}
+
+pub fn deserialize_response(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<Response, ProtocolError> {
+    let flag = next_byte(bytes)?;
+    match flag {
+        ERROR_FLAG => deserialize_error_msg(bytes).map(Response::Error),
+        TICKET_FLAG => deserialize_ticket(bytes).map(Response::Ticket),
+        HEARTBEAT_FLAG => Ok(Response::Heartbeat),
+        flag => Err(ProtocolError::UnknownMessageType(flag)),
+    }
+}
+
+pub fn serialize_response(response: &Response) -> Result<Vec<u8>, ProtocolError> {
+    match response {
+        Response::Error(msg) => serialize_error_msg(msg),
+        Response::Ticket(ticket) => serialize_ticket(ticket),
+        Response::Heartbeat => Ok(vec![HEARTBEAT_FLAG]),
+    }
+}
+
+pub fn deserialize_request(bytes: &mut impl Iterator<Item = u8>) -> Result<Request, ProtocolError> {
+    let flag = next_byte(bytes)?;
+    match flag {
+        AUTHENTICATE_FLAG => deserialize_authenticate(bytes).map(Request::Authenticate),
+        I_AM_CAMERA_FLAG => deserialize_i_am_camera(bytes).map(Request::IAmCamera),
+        I_AM_DISPATCHER_FLAG => deserialize_i_am_dispatcher(bytes).map(Request::IAmDispatcher),
+        WANT_HEARTBEAT_FLAG => deserialize_want_heartbeat(bytes).map(Request::WantHeartbeat),
+        PLATE_FLAG => deserialize_plate(bytes).map(Request::Plate),
+        flag => Err(ProtocolError::UnknownMessageType(flag)),
+    }
+}
+
+pub fn serialize_error_msg(msg: &str) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::new();
+    bytes.push(ERROR_FLAG);
+    bytes.extend(serialize_str(msg)?);
+    Ok(bytes)
+}
+
+pub fn deserialize_error_msg(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<String, ProtocolError> {
+    deserialize_str(bytes)
+}
+
+pub fn serialize_plate(plate: &Plate) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::new();
+    bytes.push(PLATE_FLAG);
+    bytes.extend(serialize_str(&plate.plate)?);
+    bytes.extend(serialize_u32(plate.timestamp));
+    Ok(bytes)
+}
+
+pub fn deserialize_plate(bytes: &mut impl Iterator<Item = u8>) -> Result<Plate, ProtocolError> {
+    let plate = Plate {
+        plate: deserialize_str(bytes)?,
+        timestamp: deserialize_u32(bytes)?,
+    };
+    Ok(plate)
+}
+
+pub fn serialize_ticket(ticket: &Ticket) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::new();
+    bytes.push(TICKET_FLAG);
+    bytes.extend(serialize_str(&ticket.plate)?);
+    bytes.extend(serialize_u16(ticket.road));
+    bytes.extend(serialize_u16(ticket.mile1));
+    bytes.extend(serialize_u32(ticket.timestamp1));
+    bytes.extend(serialize_u16(ticket.mile2));
+    bytes.extend(serialize_u32(ticket.timestamp2));
+    bytes.extend(serialize_u16(ticket.speed));
+    Ok(bytes)
+}
+
+pub fn deserialize_ticket(bytes: &mut impl Iterator<Item = u8>) -> Result<Ticket, ProtocolError> {
+    let ticket = Ticket {
+        plate: deserialize_str(bytes)?,
+        road: deserialize_u16(bytes)?,
+        mile1: deserialize_u16(bytes)?,
+        timestamp1: deserialize_u32(bytes)?,
+        mile2: deserialize_u16(bytes)?,
+        timestamp2: deserialize_u32(bytes)?,
+        speed: deserialize_u16(bytes)?,
+    };
+    Ok(ticket)
+}
+
+pub fn serialize_want_heartbeat(want_heartbeat: &WantHeartbeat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.push(WANT_HEARTBEAT_FLAG);
+    bytes.extend(serialize_u32(want_heartbeat.interval));
+    bytes
+}
+
+pub fn deserialize_want_heartbeat(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<WantHeartbeat, ProtocolError> {
+    let want_heartbeat = WantHeartbeat {
+        interval: deserialize_u32(bytes)?,
+    };
+    Ok(want_heartbeat)
+}
+
+pub fn serialize_i_am_camera(i_am_camera: &IAmCamera) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.push(I_AM_CAMERA_FLAG);
+    bytes.extend(serialize_u16(i_am_camera.road));
+    bytes.extend(serialize_u16(i_am_camera.mile));
+    bytes.extend(serialize_u16(i_am_camera.limit));
+    bytes
+}
+
+pub fn deserialize_i_am_camera(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<IAmCamera, ProtocolError> {
+    let i_am_camera = IAmCamera {
+        road: deserialize_u16(bytes)?,
+        mile: deserialize_u16(bytes)?,
+        limit: deserialize_u16(bytes)?,
+    };
+    Ok(i_am_camera)
+}
+
+pub fn serialize_i_am_dispatcher(
+    i_am_dispatcher: &IAmDispatcher,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::new();
+    bytes.push(I_AM_DISPATCHER_FLAG);
+    bytes.extend(serialize_vec(&i_am_dispatcher.roads)?);
+    Ok(bytes)
+}
+
+pub fn deserialize_i_am_dispatcher(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<IAmDispatcher, ProtocolError> {
+    let i_am_dispatcher = IAmDispatcher {
+        roads: deserialize_vec(bytes)?,
+    };
+    Ok(i_am_dispatcher)
+}
+
+pub fn serialize_authenticate(authenticate: &Authenticate) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::new();
+    bytes.push(AUTHENTICATE_FLAG);
+    bytes.extend(serialize_str(&authenticate.token)?);
+    Ok(bytes)
+}
+
+pub fn deserialize_authenticate(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<Authenticate, ProtocolError> {
+    let authenticate = Authenticate {
+        token: deserialize_str(bytes)?,
+    };
+    Ok(authenticate)
+}
+
+pub(crate) fn serialize_u16(u16: u16) -> Vec<u8> {
+    u16.to_be_bytes().to_vec()
+}
+
+pub(crate) fn deserialize_u16(bytes: &mut impl Iterator<Item = u8>) -> Result<u16, ProtocolError> {
+    let bytes = [next_byte(bytes)?, next_byte(bytes)?];
+    let u16 = u16::from_be_bytes(bytes);
+    Ok(u16)
+}
+
+pub(crate) fn serialize_u32(u32: u32) -> Vec<u8> {
+    u32.to_be_bytes().to_vec()
+}
+
+pub(crate) fn deserialize_u32(bytes: &mut impl Iterator<Item = u8>) -> Result<u32, ProtocolError> {
+    let bytes = [
+        next_byte(bytes)?,
+        next_byte(bytes)?,
+        next_byte(bytes)?,
+        next_byte(bytes)?,
+    ];
+    let u32 = u32::from_be_bytes(bytes);
+    Ok(u32)
+}
+
+pub(crate) fn serialize_str(str: &str) -> Result<Vec<u8>, ProtocolError> {
+    let len = str
+        .len()
+        .try_into()
+        .map_err(|_| ProtocolError::StringTooLong(str.len()))?;
+    if !str.is_ascii() {
+        Err(ProtocolError::NonAsciiString)?;
+    }
+    let mut bytes = Vec::new();
+    bytes.push(len);
+    bytes.extend_from_slice(str.as_bytes());
+    Ok(bytes)
+}
+
+pub(crate) fn deserialize_str(
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Result<String, ProtocolError> {
+    let len = next_byte(bytes)?.into();
+    let bytes = bytes.take(len).collect::<Vec<u8>>();
+    if bytes.len() != len {
+        Err(ProtocolError::Truncated)?;
+    }
+    if !bytes.is_ascii() {
+        Err(ProtocolError::NonAsciiString)?;
+    }
+    String::from_utf8(bytes).map_err(|_| ProtocolError::NonAsciiString)
+}
+
+fn serialize_vec(vec: &[u16]) -> Result<Vec<u8>, ProtocolError> {
+    let len = vec
+        .len()
+        .try_into()
+        .map_err(|_| ProtocolError::ListTooLong(vec.len()))?;
+    let mut bytes = Vec::new();
+    bytes.push(len);
+    for u16 in vec {
+        bytes.extend(serialize_u16(*u16));
+    }
+    Ok(bytes)
+}
+
+fn deserialize_vec(bytes: &mut impl Iterator<Item = u8>) -> Result<Vec<u16>, ProtocolError> {
+    let len = next_byte(bytes)?;
+    (0..len)
+        .map(|_| deserialize_u16(bytes))
+        .collect::<Result<Vec<u16>, ProtocolError>>()
+}
+
+pub(crate) fn next_byte(bytes: &mut impl Iterator<Item = u8>) -> Result<u8, ProtocolError> {
+    bytes.next().ok_or(ProtocolError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::deserialize_authenticate;
+    use crate::protocol::deserialize_i_am_camera;
+    use crate::protocol::deserialize_i_am_dispatcher;
+    use crate::protocol::deserialize_plate;
+    use crate::protocol::deserialize_request;
+    use crate::protocol::deserialize_response;
+    use crate::protocol::deserialize_want_heartbeat;
+    use crate::protocol::serialize_authenticate;
+    use crate::protocol::serialize_error_msg;
+    use crate::protocol::serialize_plate;
+    use crate::protocol::serialize_request;
+    use crate::protocol::serialize_response;
+    use crate::protocol::serialize_ticket;
+    use crate::protocol::Authenticate;
+    use crate::protocol::ClientCodec;
+    use crate::protocol::IAmCamera;
+    use crate::protocol::IAmDispatcher;
+    use crate::protocol::Plate;
+    use crate::protocol::ProtocolError;
+    use crate::protocol::Request;
+    use crate::protocol::Response;
+    use crate::protocol::ServerCodec;
+    use crate::protocol::Ticket;
+    use crate::protocol::WantHeartbeat;
+    use bytes::BytesMut;
+    use proptest::prelude::*;
+    use tokio_util::codec::Decoder;
+    use tokio_util::codec::Encoder;
+    #[test]
+    fn serialize_error_msg_test() {
+        let error_msg = "bad";
+        let bytes = b"\x10\x03\x62\x61\x64";
+        assert_eq!(serialize_error_msg(error_msg).unwrap(), bytes.to_vec());
+    }
+    #[test]
+    fn deserialize_plate_test() {
+        let mut bytes = b"\x07\x52\x45\x30\x35\x42\x4b\x47\x00\x01\xe2\x40"
+            .to_vec()
+            .into_iter();
+        let plate = deserialize_plate(&mut bytes).unwrap();
+        assert_eq!(plate.plate, "RE05BKG".to_owned());
+        assert_eq!(plate.timestamp, 123456);
+    }
+    #[test]
+    fn serialize_ticket_test() {
+        let ticket = Ticket {
+            plate: "RE05BKG".to_owned(),
+            road: 368,
+            mile1: 1234,
+            timestamp1: 1000000,
+            mile2: 1235,
+            timestamp2: 1000060,
+            speed: 6000,
+        };
+        let bytes = b"\x21\x07\x52\x45\x30\x35\x42\x4b\x47\x01\x70\x04\xd2\x00\x0f\x42\x40\x04\xd3\x00\x0f\x42\x7c\x17\x70";
+        assert_eq!(serialize_ticket(&ticket).unwrap(), bytes.to_vec());
+    }
+    #[test]
+    fn deserialize_want_heartbeat_test() {
+        let mut bytes = b"\x00\x00\x04\xdb".to_vec().into_iter();
+        let want_heartbeat = deserialize_want_heartbeat(&mut bytes).unwrap();
+        assert_eq!(want_heartbeat.interval, 1243);
+    }
+    #[test]
+    fn deserialize_i_am_camera_test() {
+        let mut bytes = b"\x01\x70\x04\xd2\x00\x28".to_vec().into_iter();
+        let i_am_camera = deserialize_i_am_camera(&mut bytes).unwrap();
+        assert_eq!(i_am_camera.road, 368);
+        assert_eq!(i_am_camera.mile, 1234);
+        assert_eq!(i_am_camera.limit, 40);
+    }
+    #[test]
+    fn deserialize_i_am_dispatcher_test() {
+        let mut bytes = b"\x03\x00\x42\x01\x70\x13\x88".to_vec().into_iter();
+        let i_am_dispatcher = deserialize_i_am_dispatcher(&mut bytes).unwrap();
+        assert_eq!(i_am_dispatcher.roads, vec![66, 368, 5000]);
+    }
+    #[test]
+    fn authenticate_test() {
+        let authenticate = Authenticate {
+            token: "s3cr3t".to_owned(),
+        };
+        let bytes = b"\x82\x06s3cr3t";
+        assert_eq!(
+            serialize_authenticate(&authenticate).unwrap(),
+            bytes.to_vec()
+        );
+        let mut bytes = bytes[1..].iter().copied();
+        assert_eq!(deserialize_authenticate(&mut bytes).unwrap(), authenticate);
+    }
+    #[test]
+    fn decode_request_test() {
+        let mut codec = ServerCodec;
+        let mut bytes = BytesMut::from(&b"\x20\x07\x52\x45\x30\x35\x42"[..]);
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        bytes.extend_from_slice(b"\x4b\x47\x00\x01\xe2\x40\x40\x00");
+        let Some(Request::Plate(plate)) = codec.decode(&mut bytes).unwrap() else {
+            panic!("expected a plate");
+        };
+        assert_eq!(plate.plate, "RE05BKG".to_owned());
+        assert_eq!(plate.timestamp, 123456);
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        bytes.extend_from_slice(b"\x00\x00\x0a");
+        let Some(Request::WantHeartbeat(want_heartbeat)) = codec.decode(&mut bytes).unwrap() else {
+            panic!("expected a heartbeat request");
+        };
+        assert_eq!(want_heartbeat.interval, 10);
+        assert!(bytes.is_empty());
+    }
+    #[test]
+    fn decode_unknown_request_test() {
+        let mut codec = ServerCodec;
+        let mut bytes = BytesMut::from(&b"\x21\x00"[..]);
+        let result = codec.decode(&mut bytes);
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnknownMessageType(0x21))
+        ));
+    }
+    #[test]
+    fn decode_non_ascii_plate_test() {
+        let mut codec = ServerCodec;
+        let mut bytes = BytesMut::from(&b"\x20\x01\xff\x00\x00\x00\x00"[..]);
+        let result = codec.decode(&mut bytes);
+        assert!(matches!(result, Err(ProtocolError::NonAsciiString)));
+    }
+    #[test]
+    fn decode_truncated_request_test() {
+        let mut codec = ServerCodec;
+        let mut bytes = BytesMut::from(&b"\x80\x01\x70\x04"[..]);
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        let result = codec.decode_eof(&mut bytes);
+        assert!(matches!(result, Err(ProtocolError::Truncated)));
+    }
+    #[test]
+    fn deserialize_truncated_plate_test() {
+        let mut bytes = b"\x07\x52\x45\x30".to_vec().into_iter();
+        let result = deserialize_plate(&mut bytes);
+        assert!(matches!(result, Err(ProtocolError::Truncated)));
+    }
+    #[test]
+    fn serialize_long_error_msg_test() {
+        let error_msg = "a".repeat(256);
+        let result = serialize_error_msg(&error_msg);
+        assert!(matches!(result, Err(ProtocolError::StringTooLong(256))));
+    }
+    #[test]
+    fn serialize_protocol_error_test() {
+        let error_msg = ProtocolError::NotACamera.to_string();
+        let bytes = b"\x10\x0cnot a camera";
+        assert_eq!(serialize_error_msg(&error_msg).unwrap(), bytes.to_vec());
+    }
+    #[test]
+    fn encode_response_test() {
+        let mut codec = ServerCodec;
+        let mut bytes = BytesMut::new();
+        codec.encode(Response::Heartbeat, &mut bytes).unwrap();
+        codec
+            .encode(Response::Error("bad".to_owned()), &mut bytes)
+            .unwrap();
+        assert_eq!(&bytes[..], b"\x41\x10\x03\x62\x61\x64");
+    }
+    #[test]
+    fn client_codec_requests_test() {
+        let requests = vec![
+            Request::IAmCamera(IAmCamera {
+                road: 368,
+                mile: 1234,
+                limit: 40,
+            }),
+            Request::IAmDispatcher(IAmDispatcher {
+                roads: vec![66, 368, 5000],
+            }),
+            Request::WantHeartbeat(WantHeartbeat { interval: 1243 }),
+            Request::Plate(Plate {
+                plate: "RE05BKG".to_owned(),
+                timestamp: 123456,
+            }),
+        ];
+        let mut bytes = BytesMut::new();
+        for request in requests {
+            ClientCodec.encode(request, &mut bytes).unwrap();
+        }
+        let Some(Request::IAmCamera(i_am_camera)) = ServerCodec.decode(&mut bytes).unwrap() else {
+            panic!("expected a camera");
+        };
+        assert_eq!(i_am_camera.road, 368);
+        assert_eq!(i_am_camera.mile, 1234);
+        assert_eq!(i_am_camera.limit, 40);
+        let Some(Request::IAmDispatcher(i_am_dispatcher)) = ServerCodec.decode(&mut bytes).unwrap()
+        else {
+            panic!("expected a dispatcher");
+        };
+        assert_eq!(i_am_dispatcher.roads, vec![66, 368, 5000]);
+        let Some(Request::WantHeartbeat(want_heartbeat)) = ServerCodec.decode(&mut bytes).unwrap()
+        else {
+            panic!("expected a heartbeat request");
+        };
+        assert_eq!(want_heartbeat.interval, 1243);
+        let Some(Request::Plate(plate)) = ServerCodec.decode(&mut bytes).unwrap() else {
+            panic!("expected a plate");
+        };
+        assert_eq!(plate.plate, "RE05BKG".to_owned());
+        assert_eq!(plate.timestamp, 123456);
+        assert!(bytes.is_empty());
+    }
+    #[test]
+    fn client_codec_responses_test() {
+        let ticket = Ticket {
+            plate: "RE05BKG".to_owned(),
+            road: 368,
+            mile1: 1234,
+            timestamp1: 1000000,
+            mile2: 1235,
+            timestamp2: 1000060,
+            speed: 6000,
+        };
+        let mut bytes = BytesMut::new();
+        ServerCodec
+            .encode(Response::Ticket(ticket), &mut bytes)
+            .unwrap();
+        ServerCodec.encode(Response::Heartbeat, &mut bytes).unwrap();
+        ServerCodec
+            .encode(Response::Error("bad".to_owned()), &mut bytes)
+            .unwrap();
+        let mut partial = bytes.split_to(10);
+        assert!(ClientCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(bytes);
+        let mut bytes = partial;
+        let Some(Response::Ticket(ticket)) = ClientCodec.decode(&mut bytes).unwrap() else {
+            panic!("expected a ticket");
+        };
+        assert_eq!(ticket.plate, "RE05BKG".to_owned());
+        assert_eq!(ticket.road, 368);
+        assert_eq!(ticket.mile1, 1234);
+        assert_eq!(ticket.timestamp1, 1000000);
+        assert_eq!(ticket.mile2, 1235);
+        assert_eq!(ticket.timestamp2, 1000060);
+        assert_eq!(ticket.speed, 6000);
+        assert!(matches!(
+            ClientCodec.decode(&mut bytes).unwrap(),
+            Some(Response::Heartbeat)
+        ));
+        let Some(Response::Error(msg)) = ClientCodec.decode(&mut bytes).unwrap() else {
+            panic!("expected an error");
+        };
+        assert_eq!(msg, "bad".to_owned());
+        assert!(bytes.is_empty());
+    }
+    #[test]
+    fn longest_plate_test() {
+        let plate = Plate {
+            plate: "A".repeat(255),
+            timestamp: u32::MAX,
+        };
+        let bytes = serialize_plate(&plate).unwrap();
+        assert_eq!(bytes.len(), 1 + 1 + 255 + 4);
+        let mut bytes = BytesMut::from(&bytes[..]);
+        let request = ServerCodec.decode(&mut bytes).unwrap();
+        assert_eq!(request, Some(Request::Plate(plate)));
+        let plate = Plate {
+            plate: "A".repeat(256),
+            timestamp: 0,
+        };
+        let result = serialize_plate(&plate);
+        assert!(matches!(result, Err(ProtocolError::StringTooLong(256))));
+    }
+    #[test]
+    fn empty_roads_test() {
+        let request = Request::IAmDispatcher(IAmDispatcher { roads: vec![] });
+        let bytes = serialize_request(&request).unwrap();
+        assert_eq!(bytes, b"\x81\x00");
+        let mut bytes = BytesMut::from(&bytes[..]);
+        assert_eq!(ServerCodec.decode(&mut bytes).unwrap(), Some(request));
+    }
+    #[test]
+    fn most_roads_test() {
+        let request = Request::IAmDispatcher(IAmDispatcher {
+            roads: (0..255).collect(),
+        });
+        let mut bytes = BytesMut::from(&serialize_request(&request).unwrap()[..]);
+        assert_eq!(ServerCodec.decode(&mut bytes).unwrap(), Some(request));
+        let request = Request::IAmDispatcher(IAmDispatcher {
+            roads: (0..256).collect(),
+        });
+        let result = serialize_request(&request);
+        assert!(matches!(result, Err(ProtocolError::ListTooLong(256))));
+    }
+    #[test]
+    fn max_values_test() {
+        let ticket = Ticket {
+            plate: "UN1X".to_owned(),
+            road: u16::MAX,
+            mile1: u16::MAX,
+            timestamp1: u32::MAX,
+            mile2: u16::MAX,
+            timestamp2: u32::MAX,
+            speed: u16::MAX,
+        };
+        let response = Response::Ticket(ticket);
+        let bytes = serialize_response(&response).unwrap();
+        assert_eq!(&bytes[6..], [0xff; 16]);
+        let mut bytes = BytesMut::from(&bytes[..]);
+        assert_eq!(ClientCodec.decode(&mut bytes).unwrap(), Some(response));
+        let request = Request::WantHeartbeat(WantHeartbeat { interval: u32::MAX });
+        let mut bytes = BytesMut::from(&serialize_request(&request).unwrap()[..]);
+        assert_eq!(ServerCodec.decode(&mut bytes).unwrap(), Some(request));
+    }
+    fn ascii_string() -> impl Strategy<Value = String> {
+        prop::collection::vec(0u8..0x80, 0..=255)
+            .prop_map(|bytes| String::from_utf8(bytes).unwrap())
+    }
+    fn request() -> impl Strategy<Value = Request> {
+        prop_oneof![
+            ascii_string().prop_map(|token| Request::Authenticate(Authenticate { token })),
+            any::<(u16, u16, u16)>().prop_map(|(road, mile, limit)| {
+                Request::IAmCamera(IAmCamera { road, mile, limit })
+            }),
+            prop::collection::vec(any::<u16>(), 0..=255)
+                .prop_map(|roads| Request::IAmDispatcher(IAmDispatcher { roads })),
+            any::<u32>().prop_map(|interval| Request::WantHeartbeat(WantHeartbeat { interval })),
+            (ascii_string(), any::<u32>())
+                .prop_map(|(plate, timestamp)| Request::Plate(Plate { plate, timestamp })),
+        ]
+    }
+    fn response() -> impl Strategy<Value = Response> {
+        prop_oneof![
+            ascii_string().prop_map(Response::Error),
+            (ascii_string(), any::<(u16, u16, u32, u16, u32, u16)>()).prop_map(
+                |(plate, (road, mile1, timestamp1, mile2, timestamp2, speed))| {
+                    Response::Ticket(Ticket {
+                        plate,
+                        road,
+                        mile1,
+                        timestamp1,
+                        mile2,
+                        timestamp2,
+                        speed,
+                    })
+                }
+            ),
+            Just(Response::Heartbeat),
+        ]
+    }
+    proptest! {
+        #[test]
+        fn request_round_trip_test(request in request()) {
+            let bytes = serialize_request(&request).unwrap();
+            let mut iter = bytes.into_iter();
+            prop_assert_eq!(deserialize_request(&mut iter).unwrap(), request);
+            prop_assert_eq!(iter.len(), 0);
+        }
+        #[test]
+        fn response_round_trip_test(response in response()) {
+            let bytes = serialize_response(&response).unwrap();
+            let mut iter = bytes.into_iter();
+            prop_assert_eq!(deserialize_response(&mut iter).unwrap(), response);
+            prop_assert_eq!(iter.len(), 0);
+        }
+        #[test]
+        fn codec_requests_round_trip_test(
+            requests in prop::collection::vec(request(), 0..10),
+            chunk_len in 1usize..64,
+        ) {
+            let mut encoded = BytesMut::new();
+            for request in &requests {
+                ClientCodec.encode(request.clone(), &mut encoded).unwrap();
+            }
+            // Feed the bytes in chunks, as they may arrive from the network.
+            let mut bytes = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in encoded.chunks(chunk_len) {
+                bytes.extend_from_slice(chunk);
+                while let Some(request) = ServerCodec.decode(&mut bytes).unwrap() {
+                    decoded.push(request);
+                }
+            }
+            prop_assert!(ServerCodec.decode_eof(&mut bytes).unwrap().is_none());
+            prop_assert_eq!(decoded, requests);
+        }
+        #[test]
+        fn codec_responses_round_trip_test(
+            responses in prop::collection::vec(response(), 0..10),
+            chunk_len in 1usize..64,
+        ) {
+            let mut encoded = BytesMut::new();
+            for response in &responses {
+                ServerCodec.encode(response.clone(), &mut encoded).unwrap();
+            }
+            let mut bytes = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in encoded.chunks(chunk_len) {
+                bytes.extend_from_slice(chunk);
+                while let Some(response) = ClientCodec.decode(&mut bytes).unwrap() {
+                    decoded.push(response);
+                }
+            }
+            prop_assert!(ClientCodec.decode_eof(&mut bytes).unwrap().is_none());
+            prop_assert_eq!(decoded, responses);
+        }
+        #[test]
+        fn deserialize_arbitrary_request_test(data in prop::collection::vec(any::<u8>(), 0..300)) {
+            let mut iter = data.iter().copied();
+            if let Ok(request) = deserialize_request(&mut iter) {
+                let consumed = data.len() - iter.len();
+                prop_assert_eq!(serialize_request(&request).unwrap(), &data[..consumed]);
+            }
+        }
+        #[test]
+        fn decode_arbitrary_requests_test(data in prop::collection::vec(any::<u8>(), 0..300)) {
+            let mut bytes = BytesMut::from(&data[..]);
+            while let Ok(Some(_)) = ServerCodec.decode(&mut bytes) {}
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use crate::protocol::deserialize_u32;
+use crate::protocol::next_byte;
+use crate::protocol::request_len;
+use crate::protocol::serialize_u32;
+use crate::protocol::AUTHENTICATE_FLAG;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::fs::OpenOptions;
+use tokio::io;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWriteExt;
+use tokio::io::ReadBuf;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::spawn;
+use tracing::warn;
+
+/// Bytes received from a client in a single read, along with when they arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub time: SystemTime,
+    pub bytes: Vec<u8>,
+}
+
+/// Reader that saves every byte it reads to a capture file, which holds one connection. Each chunk is stored as
+/// its time in microseconds since the epoch, its length and its bytes. The tokens of `Authenticate` requests are
+/// overwritten with `*` before they are saved.
+#[derive(Debug)]
+pub(crate) struct RecordingReader<R> {
+    inner: R,
+    /// Chunks for the task writing the capture file, so that reads never wait for the disk.
+    chunks: UnboundedSender<Chunk>,
+    redactor: Redactor,
+}
+
+/// Overwrites the tokens in the bytes read from a client, which may split requests anywhere.
+#[derive(Debug, Default)]
+struct Redactor {
+    /// Bytes of the request being read so far.
+    request: Vec<u8>,
+    /// Set once the bytes stop making sense, after which the connection is closed anyway.
+    lost: bool,
+}
+
+impl Redactor {
+    fn redact(&mut self, bytes: &mut [u8]) {
+        for byte in bytes {
+            if self.lost {
+                return;
+            }
+            self.request.push(*byte);
+            // A token follows the flag and the length of the request.
+            if self.request[0] == AUTHENTICATE_FLAG && self.request.len() > 2 {
+                *byte = b'*';
+            }
+            match request_len(&self.request) {
+                Ok(Some(len)) if self.request.len() == len => self.request.clear(),
+                Ok(_) => {}
+                Err(_) => self.lost = true,
+            }
+        }
+    }
+}
+
+/// Starts recording a new connection to a capture file in `dir`, named after its start time and peer address.
+pub(crate) fn record<R>(dir: &Path, peer_addr: SocketAddr, inner: R) -> RecordingReader<R> {
+    let start = micros(SystemTime::now());
+    let name = format!("{start}-{peer_addr}.capture").replace(':', "_");
+    let (chunks, receiver) = unbounded_channel();
+    spawn(write_capture(dir.join(name), receiver));
+    RecordingReader {
+        inner,
+        chunks,
+        redactor: Redactor::default(),
+    }
+}
+
+/// Writes the chunks of a connection to its capture file until the connection is done with. A capture that
+/// cannot be written is logged and left incomplete, without affecting the connection. Captures hold the plates
+/// seen by cameras, so only their owner may read them.
+async fn write_capture(path: PathBuf, mut chunks: UnboundedReceiver<Chunk>) {
+    let written = async {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .await?;
+        while let Some(chunk) = chunks.recv().await {
+            file.write_all(&serialize_chunk(&chunk)).await?;
+        }
+        file.flush().await
+    };
+    if let Err(err) = written.await {
+        warn!(%err, path = %path.display(), "failed to write a capture");
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RecordingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let mut bytes = buf.filled()[filled..].to_vec();
+            if !bytes.is_empty() {
+                self.redactor.redact(&mut bytes);
+                let chunk = Chunk {
+                    time: SystemTime::now(),
+                    bytes,
+                };
+                let _ = self.chunks.send(chunk);
+            }
+        }
+        poll
+    }
+}
+
+fn micros(time: SystemTime) -> u64 {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_micros() as u64
+}
+
+fn serialize_chunk(chunk: &Chunk) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend(micros(chunk.time).to_be_bytes());
+    bytes.extend(serialize_u32(chunk.bytes.len() as u32));
+    bytes.extend(&chunk.bytes);
+    bytes
+}
+
+/// Reads the chunks of a capture file. A chunk cut short, as by a crash, ends the capture.
+pub async fn read_capture(path: &Path) -> io::Result<Vec<Chunk>> {
+    let bytes = fs::read(path).await?;
+    let mut iter = bytes.into_iter();
+    let mut chunks = Vec::new();
+    while iter.len() > 0 {
+        let Some(chunk) = deserialize_chunk(&mut iter) else {
+            break;
+        };
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+fn deserialize_chunk(bytes: &mut impl Iterator<Item = u8>) -> Option<Chunk> {
+    let mut micros = [0; 8];
+    for byte in &mut micros {
+        *byte = next_byte(bytes).ok()?;
+    }
+    let time = SystemTime::UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(micros));
+    let len = deserialize_u32(bytes).ok()? as usize;
+    let chunk = bytes.take(len).collect::<Vec<u8>>();
+    if chunk.len() != len {
+        return None;
+    }
+    Some(Chunk { time, bytes: chunk })
+}
+
+/// Capture files found in `dir`, in the order their connections started.
+pub async fn list_captures(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "capture")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recorder::list_captures;
+    use crate::recorder::read_capture;
+    use crate::recorder::record;
+    use crate::test_util::temp_path;
+    use std::net::SocketAddr;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::fs;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::sleep;
+    #[tokio::test]
+    async fn record_test() {
+        let dir = temp_path("record");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir(&dir).await.unwrap();
+        let (mut client, server) = tokio::io::duplex(64);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let mut reader = record(&dir, peer_addr, server);
+        let mut bytes = [0; 7];
+        client.write_all(b"\x80\x00\x7b").await.unwrap();
+        reader.read_exact(&mut bytes[..3]).await.unwrap();
+        client.write_all(b"\x00\x08\x00\x3c").await.unwrap();
+        reader.read_exact(&mut bytes[3..]).await.unwrap();
+        drop(reader);
+        // The capture is written in the background.
+        let start = Instant::now();
+        let (paths, chunks) = loop {
+            let paths = list_captures(&dir).await.unwrap();
+            let chunks = match paths.first() {
+                Some(path) => read_capture(path).await.unwrap(),
+                None => Vec::new(),
+            };
+            if chunks.len() == 2 || start.elapsed() > Duration::from_secs(1) {
+                break (paths, chunks);
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        let [path] = &paths[..] else {
+            panic!("expected a single capture");
+        };
+        assert!(path.to_str().unwrap().ends_with("-127.0.0.1_5000.capture"));
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].bytes, b"\x80\x00\x7b");
+        assert_eq!(chunks[1].bytes, b"\x00\x08\x00\x3c");
+        assert!(chunks[0].time <= chunks[1].time);
+        let metadata = fs::metadata(path).await.unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+    #[tokio::test]
+    async fn record_redact_test() {
+        let dir = temp_path("redact");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir(&dir).await.unwrap();
+        let (mut client, server) = tokio::io::duplex(64);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let mut reader = record(&dir, peer_addr, server);
+        let mut bytes = [0; 11];
+        client.write_all(b"\x82\x06se").await.unwrap();
+        reader.read_exact(&mut bytes[..4]).await.unwrap();
+        client.write_all(b"cret\x80\x00\x7b").await.unwrap();
+        reader.read_exact(&mut bytes[4..]).await.unwrap();
+        // The server still reads the token.
+        assert_eq!(&bytes[2..8], b"secret");
+        drop(reader);
+        let start = Instant::now();
+        let chunks = loop {
+            let paths = list_captures(&dir).await.unwrap();
+            let chunks = match paths.first() {
+                Some(path) => read_capture(path).await.unwrap(),
+                None => Vec::new(),
+            };
+            if chunks.len() == 2 || start.elapsed() > Duration::from_secs(1) {
+                break chunks;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].bytes, b"\x82\x06**");
+        assert_eq!(chunks[1].bytes, b"****\x80\x00\x7b");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+    #[tokio::test]
+    async fn record_error_test() {
+        let dir = temp_path("missing");
+        let _ = fs::remove_dir_all(&dir).await;
+        let (mut client, server) = tokio::io::duplex(64);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let mut reader = record(&dir, peer_addr, server);
+        let mut bytes = [0; 3];
+        client.write_all(b"\x80\x00\x7b").await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        // The capture file cannot be created, but the connection is not affected.
+        client.write_all(b"\x00\x08\x00").await.unwrap();
+        reader.read_exact(&mut bytes).await.unwrap();
+        reader.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(&bytes, b"\x00\x08\x00");
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::Config;
+use crate::tickets::day;
+use crate::tickets::Ledger;
+use crate::tickets::Observations;
+
+/// Bounds on the sightings held in memory, beyond which they are evicted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Retention {
+    /// Seconds before the latest sighting within which every sighting is kept, so that no ticket between two
+    /// sightings within the window is missed. Without a window, sightings are kept for [`MAX_LATENESS`], and
+    /// after it only the latest of each plate on each road is.
+    window: Option<u32>,
+    /// Most sightings held, whatever the window.
+    max_sightings: Option<usize>,
+}
+
+/// Sightings evicted by a pass, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Evictions {
+    pub(crate) expired: usize,
+    pub(crate) ticketed: usize,
+    pub(crate) over_cap: usize,
+}
+
+impl Evictions {
+    pub(crate) fn total(&self) -> usize {
+        self.expired + self.ticketed + self.over_cap
+    }
+}
+
+/// Seconds before the latest sighting within which late sightings are still paired, without a retention window.
+pub(crate) const MAX_LATENESS: u32 = 86400;
+
+impl Retention {
+    pub(crate) fn from_config(config: &Config) -> Retention {
+        Retention {
+            window: config.retention_window,
+            max_sightings: config.max_observations,
+        }
+    }
+
+    /// Timestamp before which sightings arrive too late to be paired: the start of the window, or
+    /// [`MAX_LATENESS`] before the latest sighting without one. Both are measured from
+    /// [`Observations::latest`], which a single camera cannot move.
+    pub(crate) fn horizon(&self, observations: &Observations) -> Option<u32> {
+        let latest = observations.latest()?;
+        Some(latest.saturating_sub(self.window.unwrap_or(MAX_LATENESS)))
+    }
+
+    /// Whether a sighting at `timestamp` arrives too late to be paired, and should be dropped.
+    pub(crate) fn is_too_late(&self, observations: &Observations, timestamp: u32) -> bool {
+        self.horizon(observations)
+            .is_some_and(|horizon| timestamp < horizon)
+    }
+
+    pub(crate) fn is_over_cap(&self, observations: &Observations) -> bool {
+        self.max_sightings
+            .is_some_and(|max_sightings| observations.len() > max_sightings)
+    }
+
+    /// Evicts the sightings older than the window, or without one those that no sighting can still be paired
+    /// with, then those on days their plate was already ticketed, which can only give tickets that the one per day
+    /// rule suppresses. If that is not enough, the oldest sightings go until nine tenths of the cap are left, so
+    /// that the cap is not hit again at the next sighting.
+    pub(crate) fn evict(&self, observations: &mut Observations, ledger: &Ledger) -> Evictions {
+        let mut evictions = Evictions::default();
+        if let Some(horizon) = self.horizon(observations) {
+            evictions.expired = match self.window {
+                Some(_) => observations.retain(|_, _, sighting| sighting.timestamp >= horizon),
+                None => observations.prune(horizon),
+            };
+        }
+        evictions.ticketed = observations
+            .retain(|_, plate, sighting| !ledger.is_ticketed(plate, day(sighting.timestamp)));
+        if self.is_over_cap(observations) {
+            let max_sightings = self.max_sightings.unwrap_or_default();
+            let target = max_sightings - max_sightings / 10;
+            let mut timestamps = observations
+                .sightings()
+                .map(|(_, _, sighting)| sighting.timestamp)
+                .collect::<Vec<u32>>();
+            timestamps.sort_unstable();
+            let newest_evicted = timestamps[timestamps.len() - target - 1];
+            evictions.over_cap =
+                observations.retain(|_, _, sighting| sighting.timestamp > newest_evicted);
+        }
+        evictions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::IAmCamera;
+    use crate::protocol::Plate;
+    use crate::protocol::Ticket;
+    use crate::retention::Evictions;
+    use crate::retention::Retention;
+    use crate::tickets::Ledger;
+    use crate::tickets::Observations;
+    fn record(
+        observations: &mut Observations,
+        mile: u16,
+        plate: &str,
+        timestamp: u32,
+    ) -> Vec<Ticket> {
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile,
+            limit: 60,
+        };
+        let plate = Plate {
+            plate: plate.to_owned(),
+            timestamp,
+        };
+        observations.record(i_am_camera, plate)
+    }
+    #[test]
+    fn window_test() {
+        let retention = Retention {
+            window: Some(3600),
+            max_sightings: None,
+        };
+        let mut observations = Observations::default();
+        record(&mut observations, 8, "UN1X", 0);
+        record(&mut observations, 8, "RE05BKG", 1000);
+        record(&mut observations, 9, "RE05BKG", 4600);
+        record(&mut observations, 10, "UN1X", 4600);
+        let evictions = retention.evict(&mut observations, &Ledger::default());
+        assert_eq!(evictions.expired, 1);
+        assert_eq!(observations.len(), 3);
+        // The sighting at the start of the window still gives a ticket with a late one.
+        let tickets = record(&mut observations, 10, "RE05BKG", 1045);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!(tickets[0].timestamp1, 1000);
+    }
+    #[test]
+    fn lateness_test() {
+        let retention = Retention::default();
+        let mut observations = Observations::default();
+        record(&mut observations, 8, "UN1X", 0);
+        record(&mut observations, 9, "UN1X", 1000);
+        record(&mut observations, 8, "RE05BKG", 1000);
+        record(&mut observations, 8, "AB12", 100000);
+        record(&mut observations, 9, "CD34", 100000);
+        let evictions = retention.evict(&mut observations, &Ledger::default());
+        assert_eq!(evictions.expired, 1);
+        assert_eq!(observations.len(), 4);
+        assert!(retention.is_too_late(&observations, 13599));
+        assert!(!retention.is_too_late(&observations, 13600));
+        // The latest sighting before the horizon is kept, as the sightings after it are still paired with it.
+        let tickets = record(&mut observations, 400, "UN1X", 13600);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!(tickets[0].timestamp1, 1000);
+    }
+    #[test]
+    fn far_future_test() {
+        let retention = Retention::default();
+        let mut observations = Observations::default();
+        record(&mut observations, 8, "UN1X", 0);
+        record(&mut observations, 9, "UN1X", 1000);
+        record(&mut observations, 10, "AB12", u32::MAX);
+        assert_eq!(observations.latest(), Some(1000));
+        let evictions = retention.evict(&mut observations, &Ledger::default());
+        assert_eq!(evictions.total(), 0);
+        assert!(!retention.is_too_late(&observations, 0));
+        // A second camera as far ahead moves the clock.
+        record(&mut observations, 11, "AB12", u32::MAX - 100000);
+        assert_eq!(observations.latest(), Some(u32::MAX - 100000));
+        assert!(retention.is_too_late(&observations, 1000));
+    }
+    #[test]
+    fn ticketed_test() {
+        let retention = Retention::default();
+        let mut observations = Observations::default();
+        let mut ledger = Ledger::default();
+        record(&mut observations, 8, "UN1X", 0);
+        let [ticket] = record(&mut observations, 9, "UN1X", 45).try_into().unwrap();
+        assert!(ledger.issue(&ticket));
+        record(&mut observations, 8, "UN1X", 86400);
+        record(&mut observations, 8, "RE05BKG", 0);
+        let evictions = retention.evict(&mut observations, &ledger);
+        assert_eq!(evictions.ticketed, 2);
+        assert_eq!(observations.len(), 2);
+    }
+    #[test]
+    fn cap_test() {
+        let retention = Retention {
+            window: None,
+            max_sightings: Some(20),
+        };
+        let mut observations = Observations::default();
+        for timestamp in 0..20 {
+            record(&mut observations, 8, &format!("CAR{timestamp}"), timestamp);
+        }
+        assert!(!retention.is_over_cap(&observations));
+        record(&mut observations, 8, "CAR20", 20);
+        assert!(retention.is_over_cap(&observations));
+        let evictions = retention.evict(&mut observations, &Ledger::default());
+        let expected = Evictions {
+            over_cap: 3,
+            ..Evictions::default()
+        };
+        assert_eq!(evictions, expected);
+        assert_eq!(observations.len(), 18);
+        assert_eq!(
+            observations.sightings().map(|(_, _, s)| s.timestamp).min(),
+            Some(3)
+        );
+    }
+}
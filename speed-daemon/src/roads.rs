@@ -0,0 +1,167 @@
+use crate::protocol::IAmCamera;
+use crate::protocol::ProtocolError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Connected cameras and the layout of the roads they are on, which must agree on the limit of each road and
+/// not share a mile.
+#[derive(Default, Debug)]
+pub(crate) struct Roads {
+    cameras: HashMap<SocketAddr, IAmCamera>,
+    roads: BTreeMap<u16, Road>,
+}
+
+/// Limit of a road and the camera at each of its miles.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Road {
+    pub(crate) limit: u16,
+    pub(crate) cameras: BTreeMap<u16, SocketAddr>,
+}
+
+impl Roads {
+    /// Adds a camera to its road, unless it contradicts the cameras already there.
+    pub(crate) fn register(
+        &mut self,
+        peer_addr: SocketAddr,
+        i_am_camera: IAmCamera,
+    ) -> Result<(), ProtocolError> {
+        let road = self.roads.entry(i_am_camera.road).or_insert(Road {
+            limit: i_am_camera.limit,
+            cameras: BTreeMap::new(),
+        });
+        if road.limit != i_am_camera.limit {
+            return Err(ProtocolError::InconsistentLimit {
+                road: i_am_camera.road,
+                limit: i_am_camera.limit,
+                expected: road.limit,
+            });
+        }
+        if road.cameras.contains_key(&i_am_camera.mile) {
+            return Err(ProtocolError::DuplicateMile {
+                road: i_am_camera.road,
+                mile: i_am_camera.mile,
+            });
+        }
+        road.cameras.insert(i_am_camera.mile, peer_addr);
+        self.cameras.insert(peer_addr, i_am_camera);
+        Ok(())
+    }
+
+    /// Removes a camera, forgetting its road once no camera is left on it.
+    pub(crate) fn unregister(&mut self, peer_addr: SocketAddr) {
+        let Some(i_am_camera) = self.cameras.remove(&peer_addr) else {
+            return;
+        };
+        let Some(road) = self.roads.get_mut(&i_am_camera.road) else {
+            return;
+        };
+        road.cameras.remove(&i_am_camera.mile);
+        if road.cameras.is_empty() {
+            self.roads.remove(&i_am_camera.road);
+        }
+    }
+
+    pub(crate) fn cameras(&self) -> &HashMap<SocketAddr, IAmCamera> {
+        &self.cameras
+    }
+
+    pub(crate) fn layouts(&self) -> &BTreeMap<u16, Road> {
+        &self.roads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::IAmCamera;
+    use crate::protocol::ProtocolError;
+    use crate::roads::Roads;
+    use std::net::SocketAddr;
+    fn peer_addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+    #[test]
+    fn register_test() {
+        let mut roads = Roads::default();
+        let i_am_camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let i_am_camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 60,
+        };
+        roads.register(peer_addr(1), i_am_camera1).unwrap();
+        roads.register(peer_addr(2), i_am_camera2).unwrap();
+        let road = &roads.layouts()[&123];
+        assert_eq!(road.limit, 60);
+        assert_eq!(road.cameras[&8], peer_addr(1));
+        assert_eq!(road.cameras[&9], peer_addr(2));
+        assert_eq!(roads.cameras().len(), 2);
+    }
+    #[test]
+    fn inconsistent_limit_test() {
+        let mut roads = Roads::default();
+        let i_am_camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let i_am_camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 50,
+        };
+        roads.register(peer_addr(1), i_am_camera1).unwrap();
+        let err = roads.register(peer_addr(2), i_am_camera2).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::InconsistentLimit {
+                road: 123,
+                limit: 50,
+                expected: 60,
+            }
+        ));
+        assert!(!roads.layouts()[&123].cameras.contains_key(&9));
+        assert_eq!(roads.cameras().len(), 1);
+    }
+    #[test]
+    fn duplicate_mile_test() {
+        let mut roads = Roads::default();
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        roads.register(peer_addr(1), i_am_camera).unwrap();
+        let err = roads.register(peer_addr(2), i_am_camera).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::DuplicateMile { road: 123, mile: 8 }
+        ));
+        assert_eq!(roads.layouts()[&123].cameras[&8], peer_addr(1));
+    }
+    #[test]
+    fn unregister_test() {
+        let mut roads = Roads::default();
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        roads.register(peer_addr(1), i_am_camera).unwrap();
+        roads.register(peer_addr(2), i_am_camera).unwrap_err();
+        roads.unregister(peer_addr(2));
+        assert_eq!(roads.layouts()[&123].cameras[&8], peer_addr(1));
+        roads.unregister(peer_addr(1));
+        assert!(roads.layouts().is_empty());
+        let i_am_camera = IAmCamera {
+            limit: 50,
+            ..i_am_camera
+        };
+        roads.register(peer_addr(3), i_am_camera).unwrap();
+    }
+}
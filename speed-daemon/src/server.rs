@@ -0,0 +1,1313 @@
+use crate::admin;
+use crate::audit;
+use crate::audit::AuditEntry;
+use crate::audit::AuditWriter;
+use crate::audit::TicketSink;
+use crate::audit::TicketStatus;
+use crate::auth::Tokens;
+use crate::config::Config;
+use crate::dispatchers::Dispatchers;
+use crate::dispatchers::IssuedTicket;
+use crate::dispatchers::TICKET_BUFFER;
+use crate::journal::Journal;
+use crate::journal::Record;
+use crate::metrics::Metrics;
+use crate::policy;
+use crate::policy::ViolationPolicy;
+use crate::protocol::IAmCamera;
+use crate::protocol::IAmDispatcher;
+use crate::protocol::Plate;
+use crate::protocol::ProtocolError;
+use crate::protocol::Request;
+use crate::protocol::Response;
+use crate::protocol::ServerCodec;
+use crate::protocol::Ticket;
+use crate::protocol::WantHeartbeat;
+use crate::recorder;
+use crate::retention::Retention;
+use crate::roads::Roads;
+use crate::tickets::Ledger;
+use crate::tickets::Observations;
+use crate::tickets::Sighting;
+use crate::tls;
+use futures::SinkExt;
+use futures::StreamExt;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::future::pending;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
+use tokio::task::spawn;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+use tokio::time::timeout;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::FramedRead;
+use tokio_util::codec::FramedWrite;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::task_tracker::TaskTrackerToken;
+use tokio_util::task::TaskTracker;
+use tracing::debug;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Instrument;
+use tracing::Span;
+
+/// Accepts cameras and dispatchers on `listener` and serves each one on its own task. If a journal is
+/// configured, the state saved there is recovered first and everything seen from then on is saved to it.
+pub async fn run(listener: TcpListener, config: Config) -> io::Result<()> {
+    Server::new(config).await?.serve(listener).await
+}
+
+/// A running speed-daemon, whose state is shared by every listener it serves.
+#[derive(Clone)]
+pub struct Server {
+    state: Arc<State>,
+    connections: Arc<Semaphore>,
+    /// Every connection task.
+    tasks: TaskTracker,
+    /// Connections that may still produce tickets, which are all but those of dispatchers.
+    producers: TaskTracker,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Server {
+    /// Recovers the state saved in the journal, if any, and starts compacting the journal in the background.
+    pub async fn new(config: Config) -> io::Result<Server> {
+        let connections = Arc::new(Semaphore::new(config.max_connections.get()));
+        let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
+        let mut state = State::recover(config).await?;
+        if let Some(tokens_path) = &state.config.tokens {
+            state.tokens = Some(Tokens::load(tokens_path).await?);
+        }
+        if let Some(audit_path) = &state.config.audit {
+            let sink = audit::open(audit_path, state.config.audit_format)?;
+            *state.audit.lock().await = Some(AuditWriter::spawn(sink, state.metrics.clone()));
+        }
+        let state = Arc::new(state);
+        if state.journal.is_some() {
+            spawn(compact_journal(state.clone()));
+        }
+        spawn(retain_observations(state.clone()));
+        let server = Server {
+            state,
+            connections,
+            tasks: TaskTracker::new(),
+            producers: TaskTracker::new(),
+            tls,
+        };
+        Ok(server)
+    }
+
+    /// Accepts cameras and dispatchers on `listener` and serves each one on its own task, until the server is
+    /// shut down.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        self.accept(listener, None).await
+    }
+
+    /// Same as [`Server::serve`], over TLS with the certificate of the config.
+    pub async fn serve_tls(self, listener: TcpListener) -> io::Result<()> {
+        let Some(acceptor) = self.tls.clone() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS is not configured",
+            ));
+        };
+        self.accept(listener, Some(acceptor)).await
+    }
+
+    async fn accept(self, listener: TcpListener, tls: Option<TlsAcceptor>) -> io::Result<()> {
+        let shutdown = &self.state.shutdown;
+        loop {
+            let permit = select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                permit = self.connections.clone().acquire_owned() => permit,
+            };
+            let Ok(permit) = permit else {
+                return Ok(());
+            };
+            let accepted = select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(%err, "failed to accept a connection");
+                    continue;
+                }
+            };
+            let state = self.state.clone();
+            let producer = self.producers.token();
+            let tls = tls.clone();
+            let span = info_span!(
+                "connection",
+                %peer_addr,
+                tls = tls.is_some(),
+                identity = "unknown",
+            );
+            let connection = async move {
+                let _permit = permit;
+                info!("connection opened");
+                // The handshake happens on the task of the connection, so that a slow client does not hold up
+                // the others.
+                let split = select! {
+                    _ = state.shutdown.cancelled() => return,
+                    split = split(stream, tls.as_ref()) => split,
+                };
+                let (r_stream, w_stream) = match split {
+                    Ok(split) => split,
+                    Err(err) => {
+                        warn!(%err, "TLS handshake failed");
+                        state.metrics.protocol_error(&err.into());
+                        return;
+                    }
+                };
+                let w_stream = Arc::new(Mutex::new(FramedWrite::new(w_stream, ServerCodec)));
+                let mut client = Client {
+                    producer: Some(producer),
+                    ..Client::default()
+                };
+                let r_stream = open_reader(r_stream, peer_addr, &state.config);
+                let r_stream = FramedRead::new(r_stream, ServerCodec);
+                let result =
+                    handle_connection(r_stream, w_stream.clone(), peer_addr, &mut client, &state)
+                        .await;
+                state.roads.lock().await.unregister(peer_addr);
+                let mut dispatchers = state.dispatchers.lock().await;
+                let queued = match client.tickets.take() {
+                    Some(tickets) => dispatchers.unregister_with(peer_addr, tickets),
+                    None => {
+                        dispatchers.unregister(peer_addr);
+                        Vec::new()
+                    }
+                };
+                drop(dispatchers);
+                drop(client);
+                for issued in queued {
+                    state.audit(issued, TicketStatus::Queued).await;
+                }
+                match result {
+                    Ok(()) => info!(reason = "client hung up", "connection closed"),
+                    Err(ProtocolError::ShuttingDown) => {
+                        info!(reason = %ProtocolError::ShuttingDown, "connection closed");
+                        state.metrics.protocol_error(&ProtocolError::ShuttingDown);
+                        let _ = send_error_msg(&w_stream, &ProtocolError::ShuttingDown).await;
+                    }
+                    Err(err) => {
+                        warn!(reason = %err, "connection closed");
+                        state.metrics.protocol_error(&err);
+                        let _ = send_error_msg(&w_stream, &err).await;
+                    }
+                }
+            };
+            self.tasks.spawn(connection.instrument(span));
+        }
+    }
+
+    /// Stops accepting connections and closes the open ones once they are done with the message at hand, telling
+    /// clients why. Dispatchers are closed last, so that they still receive the tickets of the last plates. The
+    /// journal, if there is one, already holds every ticket not yet delivered and is compacted last. Connections
+    /// still open after [`DRAIN_TIMEOUT`] are abandoned.
+    pub async fn shutdown(&self) -> io::Result<ShutdownSummary> {
+        let connections = self.tasks.len();
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        self.state.shutdown.cancel();
+        self.producers.close();
+        let _ = timeout_at(deadline, self.producers.wait()).await;
+        self.state.drained.cancel();
+        self.tasks.close();
+        let _ = timeout_at(deadline, self.tasks.wait()).await;
+        let abandoned = self.tasks.len();
+        if abandoned > 0 {
+            warn!(abandoned, "connections did not close in time");
+        }
+        let pending_tickets = self
+            .state
+            .dispatchers
+            .lock()
+            .await
+            .pending()
+            .values()
+            .map(VecDeque::len)
+            .sum();
+        if let Some(audit) = self.state.audit.lock().await.take() {
+            audit.close().await;
+        }
+        if let Some(journal) = &self.state.journal {
+            let observations = self.state.observations.lock().await;
+            let horizon = self.state.retention.horizon(&observations);
+            let compacted = journal.compact(&observations, horizon);
+            drop(observations);
+            compacted.await?;
+        }
+        let summary = ShutdownSummary {
+            connections,
+            abandoned,
+            pending_tickets,
+            saved: self.state.journal.is_some(),
+        };
+        Ok(summary)
+    }
+
+    /// Sends every ticket emitted from now on to `sink` rather than to the audit file of the config, if any.
+    pub async fn set_ticket_sink(&self, sink: impl TicketSink + 'static) {
+        let audit = AuditWriter::spawn(Box::new(sink), self.state.metrics.clone());
+        let previous = self.state.audit.lock().await.replace(audit);
+        if let Some(previous) = previous {
+            previous.close().await;
+        }
+    }
+
+    /// Decides tickets with `policy` from now on, rather than with the policy of the config.
+    pub async fn set_violation_policy(&self, policy: impl ViolationPolicy + 'static) {
+        let mut observations = self.state.observations.lock().await;
+        observations.set_policy(Box::new(policy));
+    }
+
+    /// Answers HTTP requests for the live state and the metrics of the server on `listener`, which should only
+    /// be reachable by operators.
+    pub async fn serve_admin(self, listener: TcpListener) -> io::Result<()> {
+        admin::serve(listener, self.state).await
+    }
+}
+
+/// What was left to do when the server shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Connections open when the shutdown started.
+    pub connections: usize,
+    /// Connections still open when the server gave up waiting for them, whose tickets are lost without a journal.
+    pub abandoned: usize,
+    /// Tickets that no dispatcher was there to receive.
+    pub pending_tickets: usize,
+    /// Whether the pending tickets were saved to the journal, to be dispatched after a restart.
+    pub saved: bool,
+}
+
+/// Longest a shutdown waits for connections to close.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Read and write halves of a connection, whether plaintext or TLS.
+type Halves = (Box<dyn AsyncRead + Send + Unpin>, Box<dyn WriteHalf>);
+
+/// Splits a connection into its halves, after the TLS handshake if `tls` is given. A client that does not
+/// complete the handshake within [`HANDSHAKE_TIMEOUT`] is dropped, so that it does not hold a connection slot.
+async fn split(stream: TcpStream, tls: Option<&TlsAcceptor>) -> io::Result<Halves> {
+    match tls {
+        Some(acceptor) => {
+            let Ok(accepted) = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await else {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ));
+            };
+            let (r_stream, w_stream) = io::split(accepted?);
+            Ok((Box::new(r_stream), Box::new(w_stream)))
+        }
+        None => {
+            let (r_stream, w_stream) = stream.into_split();
+            Ok((Box::new(r_stream), Box::new(w_stream)))
+        }
+    }
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the read half of a connection, which saves what it reads if recording is configured.
+fn open_reader<R: AsyncRead + Send + Unpin + 'static>(
+    r_stream: R,
+    peer_addr: SocketAddr,
+    config: &Config,
+) -> Box<dyn AsyncRead + Send + Unpin> {
+    match &config.record {
+        Some(dir) => Box::new(recorder::record(dir, peer_addr, r_stream)),
+        None => Box::new(r_stream),
+    }
+}
+
+/// Runs the state machine of a connection until the client hangs up or breaks the protocol, or the server shuts
+/// down.
+async fn handle_connection(
+    mut r_stream: FramedRead<impl AsyncRead + Unpin, ServerCodec>,
+    w_stream: Writer,
+    peer_addr: SocketAddr,
+    client: &mut Client,
+    state: &State,
+) -> Result<(), ProtocolError> {
+    loop {
+        let closing = if client.producer.is_some() {
+            &state.shutdown
+        } else {
+            &state.drained
+        };
+        let request = select! {
+            _ = closing.cancelled() => Err(ProtocolError::ShuttingDown)?,
+            Some(ticket) = next_ticket(&mut client.tickets) => {
+                send_ticket(&w_stream, ticket, peer_addr, state).await?;
+                continue;
+            }
+            request = r_stream.next() => request,
+        };
+        let Some(request) = request else {
+            break;
+        };
+        let request = request?;
+        debug!(?request, "decoded message");
+        state.metrics.message_decoded(&request);
+        match request {
+            Request::Authenticate(authenticate) => {
+                if client.identity.is_known() {
+                    Err(ProtocolError::DuplicateIdentity)?;
+                }
+                if client.token.is_some() {
+                    Err(ProtocolError::DuplicateToken)?;
+                }
+                client.token = Some(authenticate.token);
+            }
+            Request::IAmCamera(i_am_camera) => {
+                if client.identity.is_known() {
+                    Err(ProtocolError::DuplicateIdentity)?;
+                }
+                if let Some(tokens) = &state.tokens {
+                    let allowed = client
+                        .token
+                        .as_deref()
+                        .is_some_and(|token| tokens.allows(token, &i_am_camera));
+                    if !allowed {
+                        Err(ProtocolError::Unauthorized {
+                            road: i_am_camera.road,
+                            mile: i_am_camera.mile,
+                        })?;
+                    }
+                }
+                state.roads.lock().await.register(peer_addr, i_am_camera)?;
+                client.identify(Identity::Camera(i_am_camera));
+            }
+            Request::IAmDispatcher(i_am_dispatcher) => {
+                if client.identity.is_known() {
+                    Err(ProtocolError::DuplicateIdentity)?;
+                }
+                let (sender, receiver) = channel(TICKET_BUFFER);
+                state
+                    .dispatchers
+                    .lock()
+                    .await
+                    .register(peer_addr, sender, &i_am_dispatcher.roads);
+                client.tickets = Some(receiver);
+                client.identify(Identity::Dispatcher(i_am_dispatcher));
+                client.producer = None;
+            }
+            Request::WantHeartbeat(want_heartbeat) => {
+                if client.want_heartbeat.is_some() {
+                    Err(ProtocolError::DuplicateHeartbeat)?;
+                }
+                client.want_heartbeat = Some(want_heartbeat);
+                let interval = match want_heartbeat.interval {
+                    0 => 0,
+                    interval => interval.max(state.config.min_heartbeat_interval),
+                };
+                let want_heartbeat = WantHeartbeat { interval };
+                info!(interval, "heartbeat requested");
+                client.heartbeat =
+                    Heartbeat::start(w_stream.clone(), want_heartbeat, state.metrics.clone());
+            }
+            Request::Plate(plate) => {
+                let Identity::Camera(i_am_camera) = client.identity else {
+                    Err(ProtocolError::NotACamera)?
+                };
+                if plate.plate.len() > usize::from(state.config.max_plate_len) {
+                    Err(ProtocolError::StringTooLong(plate.plate.len()))?;
+                }
+                for ticket in state.observe(i_am_camera, plate).await? {
+                    state.metrics.ticket_generated();
+                    let issued = IssuedTicket {
+                        ticket,
+                        limit: i_am_camera.limit,
+                    };
+                    if !state.issue(&issued).await? {
+                        state.metrics.ticket_suppressed();
+                        state.audit(issued, TicketStatus::Suppressed).await;
+                        continue;
+                    }
+                    // Tickets handed to a dispatcher are audited once written.
+                    if !state.dispatchers.lock().await.dispatch(issued.clone()) {
+                        state.audit(issued, TicketStatus::Queued).await;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Next ticket handed to a dispatcher, which never comes for other clients.
+async fn next_ticket(tickets: &mut Option<Receiver<IssuedTicket>>) -> Option<IssuedTicket> {
+    match tickets {
+        Some(tickets) => tickets.recv().await,
+        None => pending().await,
+    }
+}
+
+/// Writes a ticket to the dispatcher it was handed to. If that fails or the dispatcher stopped reading, the
+/// ticket is dispatched again without that dispatcher.
+async fn send_ticket(
+    w_stream: &Writer,
+    issued: IssuedTicket,
+    peer_addr: SocketAddr,
+    state: &State,
+) -> Result<(), ProtocolError> {
+    if let Err(err) = send(w_stream, Response::Ticket(issued.ticket.clone())).await {
+        let mut dispatchers = state.dispatchers.lock().await;
+        dispatchers.unregister(peer_addr);
+        let dispatched = dispatchers.dispatch(issued.clone());
+        drop(dispatchers);
+        if !dispatched {
+            state.audit(issued, TicketStatus::Queued).await;
+        }
+        return Err(err);
+    }
+    state.metrics.tickets_delivered(1);
+    state.deliver(&issued.ticket).await;
+    state.audit(issued, TicketStatus::Delivered).await;
+    Ok(())
+}
+
+/// Writes a response, giving up if the client does not take it within [`WRITE_TIMEOUT`].
+async fn send(w_stream: &Writer, response: Response) -> Result<(), ProtocolError> {
+    let sent = timeout(WRITE_TIMEOUT, async {
+        w_stream.lock().await.send(response).await
+    });
+    match sent.await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading").into()),
+    }
+}
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Heartbeat task of a connection, stopped as soon as the connection is done with.
+#[derive(Debug)]
+struct Heartbeat(JoinHandle<Result<(), ProtocolError>>);
+
+impl Heartbeat {
+    /// Starts writing heartbeats to the client, unless it asked for an interval of 0, which means no heartbeats.
+    fn start(
+        w_stream: Writer,
+        want_heartbeat: WantHeartbeat,
+        metrics: Arc<Metrics>,
+    ) -> Option<Heartbeat> {
+        if want_heartbeat.interval == 0 {
+            return None;
+        }
+        let heartbeat = handle_want_heartbeat(w_stream, want_heartbeat, metrics);
+        let handle = spawn(heartbeat.in_current_span());
+        Some(Heartbeat(handle))
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Writes a heartbeat every `interval` deciseconds until the client goes away.
+async fn handle_want_heartbeat(
+    w_stream: Writer,
+    want_heartbeat: WantHeartbeat,
+    metrics: Arc<Metrics>,
+) -> Result<(), ProtocolError> {
+    let period = Duration::from_millis(u64::from(want_heartbeat.interval) * 100);
+    let mut interval = interval(period);
+    loop {
+        interval.tick().await;
+        w_stream.lock().await.send(Response::Heartbeat).await?;
+        metrics.heartbeat_sent();
+    }
+}
+
+/// Tells the client why its connection is about to be closed. I/O errors are not reported, since the
+/// connection is already broken.
+async fn send_error_msg(w_stream: &Writer, err: &ProtocolError) -> Result<(), ProtocolError> {
+    if let ProtocolError::Io(_) = err {
+        return Ok(());
+    }
+    send(w_stream, Response::Error(err.to_string())).await
+}
+
+/// Write half of a connection, shared between the tasks that send it messages.
+pub(crate) type Writer = Arc<Mutex<FramedWrite<Box<dyn WriteHalf>, ServerCodec>>>;
+
+/// Write half of a plaintext or TLS connection.
+pub(crate) trait WriteHalf: AsyncWrite + Debug + Send + Unpin {}
+
+impl<W: AsyncWrite + Debug + Send + Unpin> WriteHalf for W {}
+
+/// Periodically rewrites the journal with only the observations still held, which retention evicts, and the
+/// tickets that may still suppress others. A failed compaction leaves the journal as it was, to be compacted at
+/// the next period.
+async fn compact_journal(state: Arc<State>) {
+    let Some(journal) = &state.journal else {
+        return;
+    };
+    let mut interval = interval(COMPACTION_PERIOD);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let observations = state.observations.lock().await;
+        let horizon = state.retention.horizon(&observations);
+        let compacted = journal.compact(&observations, horizon);
+        drop(observations);
+        if let Err(err) = compacted.await {
+            warn!(%err, "failed to compact the journal");
+        }
+    }
+}
+
+const COMPACTION_PERIOD: Duration = Duration::from_secs(600);
+
+/// Periodically evicts the sightings that are past retention.
+async fn retain_observations(state: Arc<State>) {
+    let mut interval = interval(RETENTION_PERIOD);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut observations = state.observations.lock().await;
+        let ledger = state.ledger.lock().await;
+        let evictions = state.retention.evict(&mut observations, &ledger);
+        if evictions.total() > 0 {
+            info!(
+                expired = evictions.expired,
+                ticketed = evictions.ticketed,
+                over_cap = evictions.over_cap,
+                "evicted sightings"
+            );
+        }
+    }
+}
+
+const RETENTION_PERIOD: Duration = Duration::from_secs(60);
+
+/// State shared by every connection.
+#[derive(Default, Debug)]
+pub(crate) struct State {
+    pub(crate) roads: Mutex<Roads>,
+    pub(crate) observations: Mutex<Observations>,
+    pub(crate) dispatchers: Mutex<Dispatchers>,
+    pub(crate) ledger: Mutex<Ledger>,
+    pub(crate) metrics: Arc<Metrics>,
+    audit: Mutex<Option<AuditWriter>>,
+    /// Tokens that cameras must present, if authentication is required.
+    tokens: Option<Tokens>,
+    retention: Retention,
+    /// Cancelled when the server starts shutting down, which closes every connection but those of dispatchers.
+    shutdown: CancellationToken,
+    /// Cancelled once no more tickets can be produced during a shutdown, which closes dispatchers.
+    drained: CancellationToken,
+    journal: Option<Journal>,
+    config: Config,
+}
+
+impl State {
+    /// Rebuilds the observations, the ledger and the pending tickets from the journal, if any.
+    async fn recover(config: Config) -> io::Result<State> {
+        let mut observations = Observations::new(policy::from_config(&config));
+        let Some(journal_path) = config.journal.clone() else {
+            let state = State {
+                observations: Mutex::new(observations),
+                retention: Retention::from_config(&config),
+                config,
+                ..State::default()
+            };
+            return Ok(state);
+        };
+        let (journal, records) = Journal::open(journal_path).await?;
+        let mut ledger = Ledger::default();
+        let mut pending = Vec::new();
+        for record in records {
+            match record {
+                Record::Observation {
+                    road,
+                    plate,
+                    sighting,
+                } => observations.restore(road, plate, sighting),
+                Record::Ticket(ticket) => {
+                    ledger.issue(&ticket);
+                }
+                Record::Pending(issued) => {
+                    ledger.issue(&issued.ticket);
+                    pending.push(issued);
+                }
+                Record::Delivered(ticket) => pending.retain(|issued| issued.ticket != ticket),
+            }
+        }
+        // Tickets issued but never written to a dispatcher are queued again, in the order they were issued.
+        let mut dispatchers = Dispatchers::default();
+        for issued in pending {
+            dispatchers.queue(issued);
+        }
+        let state = State {
+            observations: Mutex::new(observations),
+            retention: Retention::from_config(&config),
+            dispatchers: Mutex::new(dispatchers),
+            ledger: Mutex::new(ledger),
+            journal: Some(journal),
+            config,
+            ..State::default()
+        };
+        Ok(state)
+    }
+
+    /// Records a plate seen by a camera and returns the tickets it produced, once it is saved to the journal. The
+    /// sighting is handed to the journal under the lock, in the same order as to compactions, but waited for only
+    /// once the lock is released.
+    async fn observe(
+        &self,
+        i_am_camera: IAmCamera,
+        plate: Plate,
+    ) -> Result<Vec<Ticket>, ProtocolError> {
+        let mut observations = self.observations.lock().await;
+        if self.retention.is_too_late(&observations, plate.timestamp) {
+            debug!(
+                plate = plate.plate,
+                timestamp = plate.timestamp,
+                "dropped a late sighting"
+            );
+            return Ok(Vec::new());
+        }
+        let journaled = self.journal.as_ref().map(|journal| {
+            let record = Record::Observation {
+                road: i_am_camera.road,
+                plate: plate.plate.clone(),
+                sighting: Sighting {
+                    mile: i_am_camera.mile,
+                    timestamp: plate.timestamp,
+                },
+            };
+            journal.append(&record)
+        });
+        let tickets = observations.record(i_am_camera, plate);
+        if self.retention.is_over_cap(&observations) {
+            let ledger = self.ledger.lock().await;
+            let evictions = self.retention.evict(&mut observations, &ledger);
+            warn!(
+                expired = evictions.expired,
+                ticketed = evictions.ticketed,
+                over_cap = evictions.over_cap,
+                "evicted sightings to stay under the cap"
+            );
+        }
+        drop(observations);
+        if let Some(journaled) = journaled {
+            journaled.await?;
+        }
+        Ok(tickets)
+    }
+
+    /// Checks a ticket against the one per day rule, saving it to the journal as pending if it is to be issued.
+    /// If it cannot be saved, it is not issued and its days are left free.
+    async fn issue(&self, issued: &IssuedTicket) -> Result<bool, ProtocolError> {
+        let mut ledger = self.ledger.lock().await;
+        if !ledger.issue(&issued.ticket) {
+            return Ok(false);
+        }
+        let journaled = self
+            .journal
+            .as_ref()
+            .map(|journal| journal.append(&Record::Pending(issued.clone())));
+        drop(ledger);
+        if let Some(journaled) = journaled {
+            if let Err(err) = journaled.await {
+                self.ledger.lock().await.revoke(&issued.ticket);
+                Err(err)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Marks a ticket as written to a dispatcher in the journal, if any, so that it is not dispatched again after
+    /// a restart. Failing to do so is only logged, since the ticket is delivered either way.
+    async fn deliver(&self, ticket: &Ticket) {
+        if let Some(journal) = &self.journal {
+            let record = Record::Delivered(ticket.clone());
+            if let Err(err) = journal.append(&record).await {
+                warn!(%err, "failed to journal a delivered ticket");
+            }
+        }
+    }
+
+    /// Adds a ticket to the audit trail, if one is kept. The entry is written in the background, and failing to
+    /// write it does not affect the ticket.
+    async fn audit(&self, issued: IssuedTicket, status: TicketStatus) {
+        if let Some(audit) = self.audit.lock().await.as_ref() {
+            let entry = AuditEntry {
+                ticket: issued.ticket,
+                limit: issued.limit,
+                status,
+            };
+            audit.write(entry);
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Client {
+    /// Token presented before identifying.
+    token: Option<String>,
+    identity: Identity,
+    /// Tickets handed to the client once it identifies as a dispatcher.
+    tickets: Option<Receiver<IssuedTicket>>,
+    want_heartbeat: Option<WantHeartbeat>,
+    heartbeat: Option<Heartbeat>,
+    /// Held until the client identifies as a dispatcher, as it might produce tickets until then.
+    producer: Option<TaskTrackerToken>,
+}
+
+impl Client {
+    fn identify(&mut self, identity: Identity) {
+        match &identity {
+            Identity::Unknown => {}
+            Identity::Camera(i_am_camera) => {
+                Span::current().record("identity", "camera");
+                info!(
+                    road = i_am_camera.road,
+                    mile = i_am_camera.mile,
+                    limit = i_am_camera.limit,
+                    "identified as a camera"
+                );
+            }
+            Identity::Dispatcher(i_am_dispatcher) => {
+                Span::current().record("identity", "dispatcher");
+                info!(roads = ?i_am_dispatcher.roads, "identified as a dispatcher");
+            }
+        }
+        self.identity = identity;
+    }
+}
+
+/// What a client said it is, which it may only say once.
+#[derive(Default, Debug)]
+enum Identity {
+    #[default]
+    Unknown,
+    Camera(IAmCamera),
+    Dispatcher(IAmDispatcher),
+}
+
+impl Identity {
+    fn is_known(&self) -> bool {
+        !matches!(self, Identity::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditEntry;
+    use crate::audit::TicketSink;
+    use crate::client::CameraClient;
+    use crate::client::DispatcherClient;
+    use crate::config::AuditFormat;
+    use crate::config::Config;
+    use crate::config::TlsConfig;
+    use crate::protocol::IAmCamera;
+    use crate::protocol::IAmDispatcher;
+    use crate::protocol::Plate;
+    use crate::protocol::ProtocolError;
+    use crate::protocol::Response;
+    use crate::protocol::ServerCodec;
+    use crate::protocol::WantHeartbeat;
+    use crate::server::Heartbeat;
+    use crate::server::Server;
+    use crate::server::WriteHalf;
+    use crate::test_util::serve;
+    use crate::test_util::start_server;
+    use crate::test_util::temp_path;
+    use rcgen::CertifiedKey;
+    use rustls::crypto::ring;
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use rustls::RootCertStore;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::fs;
+    use tokio::io;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio::sync::Mutex;
+    use tokio::task::spawn;
+    use tokio::time::timeout;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+    use tokio_util::codec::FramedWrite;
+    #[tokio::test]
+    async fn heartbeat_interval_test() {
+        let addr = start_server(Config::default()).await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        dispatcher.want_heartbeat(2).await.unwrap();
+        let mut times = Vec::new();
+        for _ in 0..3 {
+            let response = dispatcher.recv().await.unwrap().unwrap();
+            assert!(matches!(response, Response::Heartbeat));
+            times.push(Instant::now());
+        }
+        let elapsed = times[2] - times[0];
+        assert!(elapsed >= Duration::from_millis(380), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(800), "{elapsed:?}");
+    }
+    #[tokio::test]
+    async fn heartbeat_min_interval_test() {
+        let config = Config {
+            min_heartbeat_interval: 5,
+            ..Config::default()
+        };
+        let addr = start_server(config).await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        dispatcher.want_heartbeat(1).await.unwrap();
+        dispatcher.recv().await.unwrap().unwrap();
+        let start = Instant::now();
+        dispatcher.recv().await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+    #[tokio::test]
+    async fn max_plate_len_test() {
+        let config = Config {
+            max_plate_len: 4,
+            ..Config::default()
+        };
+        let addr = start_server(config).await;
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+        let plate = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        camera.send_plate(plate).await.unwrap();
+        let plate = Plate {
+            plate: "RE05BKG".to_owned(),
+            timestamp: 0,
+        };
+        camera.send_plate(plate).await.unwrap();
+        let Some(Ok(Response::Error(msg))) = camera.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(msg, "string of 7 bytes is too long".to_owned());
+    }
+    #[tokio::test]
+    async fn inconsistent_limit_test() {
+        let addr = start_server(Config::default()).await;
+        let i_am_camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let i_am_camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 50,
+        };
+        let mut camera1 = CameraClient::connect(addr, i_am_camera1).await.unwrap();
+        camera1.want_heartbeat(1).await.unwrap();
+        camera1.recv().await.unwrap().unwrap();
+        let mut camera2 = CameraClient::connect(addr, i_am_camera2).await.unwrap();
+        let Some(Ok(Response::Error(msg))) = camera2.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(msg, "limit 50 differs from limit 60 of road 123".to_owned());
+        assert!(camera2.recv().await.is_none());
+    }
+    #[tokio::test]
+    async fn audit_test() {
+        let path = temp_path("audit");
+        let _ = fs::remove_file(&path).await;
+        let config = Config {
+            audit: Some(path.clone()),
+            audit_format: AuditFormat::JsonLines,
+            ..Config::default()
+        };
+        let server = Server::new(config).await.unwrap();
+        let addr = serve(server.clone()).await;
+        for (mile, timestamp) in [(8, 0), (9, 45), (10, 90)] {
+            let i_am_camera = IAmCamera {
+                road: 123,
+                mile,
+                limit: 60,
+            };
+            let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+            let plate = Plate {
+                plate: "UN1X".to_owned(),
+                timestamp,
+            };
+            camera.send_plate(plate).await.unwrap();
+            camera.want_heartbeat(1).await.unwrap();
+            camera.recv().await.unwrap().unwrap();
+        }
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        dispatcher.recv_ticket().await.unwrap().unwrap();
+        // Entries are written in the background, until the server shuts down.
+        server.shutdown().await.unwrap();
+        let audit = fs::read_to_string(&path).await.unwrap();
+        let lines = audit.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains(r#""mile1":8,"#));
+        assert!(lines[0].ends_with(r#""limit":60,"status":"queued"}"#));
+        assert!(lines[1].contains(r#""mile1":9,"#));
+        assert!(lines[1].ends_with(r#""limit":60,"status":"suppressed"}"#));
+        assert!(lines[2].contains(r#""mile1":8,"#));
+        assert!(lines[2].ends_with(r#""limit":60,"status":"delivered"}"#));
+    }
+    #[derive(Debug)]
+    struct FailingSink;
+    impl TicketSink for FailingSink {
+        fn write(&mut self, _: &AuditEntry) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+    #[tokio::test]
+    async fn audit_error_test() {
+        let server = Server::new(Config::default()).await.unwrap();
+        server.set_ticket_sink(FailingSink).await;
+        let addr = serve(server.clone()).await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        for (mile, timestamp) in [(8, 0), (9, 45), (10, 86400), (11, 86445)] {
+            let i_am_camera = IAmCamera {
+                road: 123,
+                mile,
+                limit: 60,
+            };
+            let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+            let plate = Plate {
+                plate: "UN1X".to_owned(),
+                timestamp,
+            };
+            camera.send_plate(plate).await.unwrap();
+            camera.want_heartbeat(1).await.unwrap();
+            camera.recv().await.unwrap().unwrap();
+        }
+        // Neither ticket is lost to the failing audit trail, nor are the cameras disconnected.
+        let ticket1 = dispatcher.recv_ticket().await.unwrap().unwrap();
+        let ticket2 = dispatcher.recv_ticket().await.unwrap().unwrap();
+        assert_eq!((ticket1.mile1, ticket2.mile1), (8, 10));
+        server.shutdown().await.unwrap();
+        let metrics = server.state.metrics.render(0);
+        assert!(metrics.contains("speed_daemon_audit_errors_total 2\n"));
+    }
+    #[tokio::test]
+    async fn tokens_test() {
+        let path = temp_path("tokens");
+        let tokens = r#"
+            [[camera]]
+            token = "s3cr3t"
+            road = 123
+            mile = 8
+        "#;
+        fs::write(&path, tokens).await.unwrap();
+        let config = Config {
+            tokens: Some(path.clone()),
+            ..Config::default()
+        };
+        let addr = start_server(config).await;
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let unauthorized = [
+            CameraClient::connect(addr, i_am_camera).await.unwrap(),
+            CameraClient::connect_with_token(addr, "0th3r".to_owned(), i_am_camera)
+                .await
+                .unwrap(),
+            CameraClient::connect_with_token(
+                addr,
+                "s3cr3t".to_owned(),
+                IAmCamera {
+                    mile: 9,
+                    ..i_am_camera
+                },
+            )
+            .await
+            .unwrap(),
+        ];
+        for mut camera in unauthorized {
+            let response = camera.recv().await.unwrap().unwrap();
+            let Response::Error(msg) = response else {
+                panic!("expected an error, got {response:?}");
+            };
+            assert!(msg.starts_with("not authorized"), "{msg}");
+            assert!(camera.recv().await.is_none());
+        }
+        let mut camera = CameraClient::connect_with_token(addr, "s3cr3t".to_owned(), i_am_camera)
+            .await
+            .unwrap();
+        camera.want_heartbeat(1).await.unwrap();
+        let response = camera.recv().await.unwrap().unwrap();
+        assert!(matches!(response, Response::Heartbeat));
+        fs::remove_file(&path).await.unwrap();
+    }
+    #[tokio::test]
+    async fn tls_test() {
+        let dir = temp_path("tls");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir(&dir).await.unwrap();
+        let CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        fs::write(dir.join("cert.pem"), cert.pem()).await.unwrap();
+        fs::write(dir.join("key.pem"), signing_key.serialize_pem())
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            tls: Some(TlsConfig {
+                listen: addr,
+                cert: dir.join("cert.pem"),
+                key: dir.join("key.pem"),
+            }),
+            ..Config::default()
+        };
+        let server = Server::new(config).await.unwrap();
+        spawn(server.serve_tls(listener));
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = connect_tls(addr, &connector).await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut dispatcher = DispatcherClient::new(stream, i_am_dispatcher)
+            .await
+            .unwrap();
+        for (mile, timestamp) in [(8, 0), (9, 45)] {
+            let stream = connect_tls(addr, &connector).await;
+            let i_am_camera = IAmCamera {
+                road: 123,
+                mile,
+                limit: 60,
+            };
+            let mut camera = CameraClient::new(stream, i_am_camera).await.unwrap();
+            let plate = Plate {
+                plate: "UN1X".to_owned(),
+                timestamp,
+            };
+            camera.send_plate(plate).await.unwrap();
+            camera.want_heartbeat(1).await.unwrap();
+            camera.recv().await.unwrap().unwrap();
+        }
+        let ticket = dispatcher.recv_ticket().await.unwrap().unwrap();
+        assert_eq!(ticket.plate, "UN1X");
+        assert_eq!(ticket.speed, 8000);
+        let mut plaintext = TcpStream::connect(addr).await.unwrap();
+        plaintext.write_all(&[0x40, 0, 0, 0, 1]).await.unwrap();
+        let mut bytes = Vec::new();
+        let read = timeout(Duration::from_secs(1), plaintext.read_to_end(&mut bytes)).await;
+        assert!(read.is_ok());
+        assert!(!bytes.contains(&0x41));
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+    async fn connect_tls(addr: SocketAddr, connector: &TlsConnector) -> TlsStream<TcpStream> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        connector.connect(server_name, stream).await.unwrap()
+    }
+    #[tokio::test]
+    async fn shutdown_test() {
+        let path = temp_path("shutdown");
+        let _ = fs::remove_file(&path).await;
+        let config = Config {
+            journal: Some(path.clone()),
+            ..Config::default()
+        };
+        let server = Server::new(config.clone()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = spawn(server.clone().serve(listener));
+        let i_am_dispatcher = IAmDispatcher { roads: vec![124] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        let mut cameras = Vec::new();
+        for (mile, timestamp) in [(8, 0), (9, 45)] {
+            let i_am_camera = IAmCamera {
+                road: 123,
+                mile,
+                limit: 60,
+            };
+            let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+            let plate = Plate {
+                plate: "UN1X".to_owned(),
+                timestamp,
+            };
+            camera.send_plate(plate).await.unwrap();
+            camera.want_heartbeat(10).await.unwrap();
+            camera.recv().await.unwrap().unwrap();
+            cameras.push(camera);
+        }
+        dispatcher.want_heartbeat(10).await.unwrap();
+        dispatcher.recv().await.unwrap().unwrap();
+        let summary = server.shutdown().await.unwrap();
+        assert_eq!(summary.connections, 3);
+        assert_eq!(summary.abandoned, 0);
+        assert_eq!(summary.pending_tickets, 1);
+        assert!(summary.saved);
+        serving.await.unwrap().unwrap();
+        for mut camera in cameras {
+            let Some(Ok(Response::Error(msg))) = camera.recv().await else {
+                panic!("expected an error");
+            };
+            assert_eq!(msg, "server shutting down".to_owned());
+            assert!(camera.recv().await.is_none());
+        }
+        let Some(Err(ProtocolError::Rejected(msg))) = dispatcher.recv_ticket().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(msg, "server shutting down".to_owned());
+        assert!(TcpStream::connect(addr).await.is_err());
+        let addr = start_server(config.clone()).await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        let ticket = dispatcher.recv_ticket().await.unwrap().unwrap();
+        assert_eq!(ticket.plate, "UN1X".to_owned());
+        assert_eq!(ticket.mile1, 8);
+        assert_eq!(ticket.mile2, 9);
+        dispatcher.want_heartbeat(1).await.unwrap();
+        dispatcher.recv().await.unwrap().unwrap();
+        // Once delivered, the ticket saved by the shutdown is not dispatched again.
+        let addr = start_server(config).await;
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        dispatcher.want_heartbeat(1).await.unwrap();
+        let response = dispatcher.recv().await.unwrap().unwrap();
+        assert!(matches!(response, Response::Heartbeat));
+        fs::remove_file(&path).await.unwrap();
+    }
+    #[tokio::test]
+    async fn recover_test() {
+        let path = temp_path("recover");
+        let _ = fs::remove_file(&path).await;
+        let config = Config {
+            journal: Some(path.clone()),
+            ..Config::default()
+        };
+        let addr = start_server(config.clone()).await;
+        let mut cameras = Vec::new();
+        for mile in [8, 9] {
+            let i_am_camera = IAmCamera {
+                road: 123,
+                mile,
+                limit: 60,
+            };
+            cameras.push(CameraClient::connect(addr, i_am_camera).await.unwrap());
+        }
+        for (camera, timestamp) in cameras.iter_mut().zip([0, 45]) {
+            let plate = Plate {
+                plate: "UN1X".to_owned(),
+                timestamp,
+            };
+            camera.send_plate(plate).await.unwrap();
+        }
+        let i_am_dispatcher = IAmDispatcher { roads: vec![123] };
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher.clone())
+            .await
+            .unwrap();
+        let ticket = dispatcher.recv_ticket().await.unwrap().unwrap();
+        assert_eq!(ticket.plate, "UN1X".to_owned());
+        // The delivery is journaled before the heartbeat request is handled.
+        dispatcher.want_heartbeat(1).await.unwrap();
+        dispatcher.recv().await.unwrap().unwrap();
+        drop(dispatcher);
+        for (camera, timestamp) in cameras.iter_mut().zip([0, 45]) {
+            let plate = Plate {
+                plate: "RE05BKG".to_owned(),
+                timestamp,
+            };
+            camera.send_plate(plate).await.unwrap();
+            camera.want_heartbeat(1).await.unwrap();
+            camera.recv().await.unwrap().unwrap();
+        }
+        // A server started on the same journal, as after a crash, only sends the ticket that was never delivered.
+        let addr = start_server(config).await;
+        let mut dispatcher = DispatcherClient::connect(addr, i_am_dispatcher)
+            .await
+            .unwrap();
+        let ticket = dispatcher.recv_ticket().await.unwrap().unwrap();
+        assert_eq!(ticket.plate, "RE05BKG".to_owned());
+        dispatcher.want_heartbeat(1).await.unwrap();
+        let response = dispatcher.recv().await.unwrap().unwrap();
+        assert!(matches!(response, Response::Heartbeat));
+        fs::remove_file(&path).await.unwrap();
+    }
+    #[tokio::test]
+    async fn heartbeat_zero_interval_test() {
+        let addr = start_server(Config::default()).await;
+        let i_am_camera = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let mut camera = CameraClient::connect(addr, i_am_camera).await.unwrap();
+        camera.want_heartbeat(0).await.unwrap();
+        let result = timeout(Duration::from_millis(300), camera.recv()).await;
+        assert!(result.is_err());
+    }
+    #[tokio::test]
+    async fn heartbeat_stop_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, w_stream) = server.into_split();
+        let w_stream: Box<dyn WriteHalf> = Box::new(w_stream);
+        let w_stream = Arc::new(Mutex::new(FramedWrite::new(w_stream, ServerCodec)));
+        let want_heartbeat = WantHeartbeat { interval: 1 };
+        let heartbeat = Heartbeat::start(w_stream, want_heartbeat, Arc::default()).unwrap();
+        let mut bytes = [0; 2];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x41, 0x41]);
+        drop(heartbeat);
+        let mut bytes = Vec::new();
+        let read = timeout(Duration::from_secs(1), client.read_to_end(&mut bytes)).await;
+        assert!(read.unwrap().unwrap() <= 1);
+    }
+}
@@ -0,0 +1,25 @@
+use crate::config::Config;
+use crate::server::Server;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use tokio::task::spawn;
+
+/// Serves cameras and dispatchers with `server` on a free local port and returns its address.
+pub(crate) async fn serve(server: Server) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn(server.serve(listener));
+    addr
+}
+
+/// Starts a server with `config` on a free local port and returns its address.
+pub(crate) async fn start_server(config: Config) -> SocketAddr {
+    serve(Server::new(config).await.unwrap()).await
+}
+
+/// Path in the temporary directory for the files of a test, unique to `name` and to this process.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("speed-daemon-{}-{name}", std::process::id()))
+}
@@ -0,0 +1,557 @@
+use crate::policy::Journey;
+use crate::policy::SpeedTolerance;
+use crate::policy::ViolationPolicy;
+use crate::protocol::IAmCamera;
+use crate::protocol::Plate;
+use crate::protocol::Ticket;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+
+/// Days on which each plate has already been ticketed, since a car gets at most one ticket per day.
+#[derive(Default, Debug)]
+pub(crate) struct Ledger {
+    days: HashMap<String, HashSet<u32>>,
+}
+
+impl Ledger {
+    /// Records the days spanned by a ticket, unless the plate was already ticketed on any of them.
+    /// Returns whether the ticket should be issued.
+    pub(crate) fn issue(&mut self, ticket: &Ticket) -> bool {
+        let days = day(ticket.timestamp1)..=day(ticket.timestamp2);
+        let ticketed_days = self.days.entry(ticket.plate.clone()).or_default();
+        if days.clone().any(|day| ticketed_days.contains(&day)) {
+            return false;
+        }
+        ticketed_days.extend(days);
+        true
+    }
+
+    /// Forgets the days of a ticket that was issued but could not be saved, so that it suppresses nothing.
+    pub(crate) fn revoke(&mut self, ticket: &Ticket) {
+        if let Some(ticketed_days) = self.days.get_mut(&ticket.plate) {
+            for day in day(ticket.timestamp1)..=day(ticket.timestamp2) {
+                ticketed_days.remove(&day);
+            }
+        }
+    }
+
+    /// Number of tickets covering each day. A ticket spanning several days counts towards each of them.
+    pub(crate) fn tickets_per_day(&self) -> BTreeMap<u32, usize> {
+        let mut tickets_per_day = BTreeMap::new();
+        for day in self.days.values().flatten() {
+            *tickets_per_day.entry(*day).or_default() += 1;
+        }
+        tickets_per_day
+    }
+
+    /// Whether `plate` was ticketed on `day`, after which its sightings that day can only give suppressed tickets.
+    pub(crate) fn is_ticketed(&self, plate: &str, day: u32) -> bool {
+        self.days
+            .get(plate)
+            .is_some_and(|ticketed_days| ticketed_days.contains(&day))
+    }
+}
+
+pub(crate) fn day(timestamp: u32) -> u32 {
+    timestamp / 86400
+}
+
+/// Hundredths of miles per hour over the limit from which a car is speeding, unless configured otherwise.
+pub(crate) const SPEED_TOLERANCE: u16 = 50;
+
+/// Plate sightings grouped by road and plate and sorted by timestamp, used to compute the average speed between
+/// cameras.
+#[derive(Debug)]
+pub(crate) struct Observations {
+    sightings: HashMap<(u16, String), BTreeSet<Sighting>>,
+    /// Number of sightings held.
+    len: usize,
+    /// The two cameras that saw the latest timestamps, with them. The latest timestamp of the second stands for
+    /// the current time, so that one camera with its clock far ahead cannot expire every other sighting.
+    leaders: [Option<((u16, u16), u32)>; 2],
+    policy: Box<dyn ViolationPolicy>,
+}
+
+impl Default for Observations {
+    fn default() -> Self {
+        Observations::new(Box::new(SpeedTolerance::default()))
+    }
+}
+
+impl Observations {
+    pub(crate) fn new(policy: Box<dyn ViolationPolicy>) -> Self {
+        Observations {
+            sightings: HashMap::new(),
+            len: 0,
+            leaders: [None; 2],
+            policy,
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: Box<dyn ViolationPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Records a plate seen by a camera and returns the tickets the policy gives for the journeys between it and
+    /// the sightings just before and after it on the road. Sightings may arrive in any order, but a ticket given
+    /// for two sightings stands even if a later one lands between them. Out of order, a car may then get tickets
+    /// that it would not get in order, each spanning a stretch on which it would have.
+    pub(crate) fn record(&mut self, i_am_camera: IAmCamera, plate: Plate) -> Vec<Ticket> {
+        let sighting = Sighting {
+            timestamp: plate.timestamp,
+            mile: i_am_camera.mile,
+        };
+        self.advance((i_am_camera.road, i_am_camera.mile), sighting.timestamp);
+        let key = (i_am_camera.road, plate.plate);
+        let sightings = self.sightings.entry(key.clone()).or_default();
+        if !sightings.insert(sighting) {
+            return Vec::new();
+        }
+        self.len += 1;
+        let previous = sightings.range(..sighting).next_back().copied();
+        let next = sightings
+            .range((Bound::Excluded(sighting), Bound::Unbounded))
+            .next()
+            .copied();
+        let (road, plate) = key;
+        [previous, next]
+            .into_iter()
+            .flatten()
+            .filter_map(|neighbour| {
+                let journey = journey(road, i_am_camera.limit, neighbour, sighting)?;
+                if !self.policy.is_violation(&journey) {
+                    return None;
+                }
+                let ticket = Ticket {
+                    plate: plate.clone(),
+                    road,
+                    mile1: journey.mile1,
+                    timestamp1: journey.timestamp1,
+                    mile2: journey.mile2,
+                    timestamp2: journey.timestamp2,
+                    speed: journey.speed.try_into().unwrap_or(u16::MAX),
+                };
+                Some(ticket)
+            })
+            .collect()
+    }
+
+    /// Puts back a sighting recovered from the journal, without checking for speeding.
+    pub(crate) fn restore(&mut self, road: u16, plate: String, sighting: Sighting) {
+        let sightings = self.sightings.entry((road, plate)).or_default();
+        if sightings.insert(sighting) {
+            self.len += 1;
+            self.advance((road, sighting.mile), sighting.timestamp);
+        }
+    }
+
+    /// Moves the clock of `camera` forward to `timestamp`, keeping track of the two cameras furthest ahead.
+    fn advance(&mut self, camera: (u16, u16), timestamp: u32) {
+        let [first, second] = &mut self.leaders;
+        match (*first, *second) {
+            (Some((leader, latest)), _) if leader == camera => {
+                *first = Some((camera, latest.max(timestamp)));
+            }
+            (Some((_, latest)), _) if timestamp <= latest => match *second {
+                Some((runner_up, latest)) if runner_up == camera || timestamp > latest => {
+                    *second = Some((camera, latest.max(timestamp)));
+                }
+                Some(_) => {}
+                None => *second = Some((camera, timestamp)),
+            },
+            _ => {
+                *second = *first;
+                *first = Some((camera, timestamp));
+            }
+        }
+    }
+
+    /// Forgets the sightings older than `oldest`, but for the latest of them for each plate on each road, which
+    /// sightings arriving in order, or late but not older than `oldest`, may still be paired with. Returns how
+    /// many were forgotten.
+    pub(crate) fn prune(&mut self, oldest: u32) -> usize {
+        let len = self.len;
+        let oldest = Sighting {
+            timestamp: oldest,
+            mile: 0,
+        };
+        for sightings in self.sightings.values_mut() {
+            let mut kept = sightings.split_off(&oldest);
+            if let Some(previous) = sightings.pop_last() {
+                kept.insert(previous);
+            }
+            *sightings = kept;
+        }
+        self.len = self.sightings.values().map(BTreeSet::len).sum();
+        len - self.len
+    }
+
+    /// Forgets the sightings for which `keep` returns false, returning how many there were.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(u16, &str, Sighting) -> bool) -> usize {
+        let len = self.len;
+        self.sightings.retain(|(road, plate), sightings| {
+            sightings.retain(|sighting| keep(*road, plate, *sighting));
+            !sightings.is_empty()
+        });
+        self.len = self.sightings.values().map(BTreeSet::len).sum();
+        len - self.len
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Latest timestamp seen by all cameras but the one furthest ahead, or by the only camera there is.
+    pub(crate) fn latest(&self) -> Option<u32> {
+        let [first, second] = self.leaders;
+        second.or(first).map(|(_, latest)| latest)
+    }
+
+    pub(crate) fn sightings(&self) -> impl Iterator<Item = (u16, &str, Sighting)> {
+        self.sightings
+            .iter()
+            .flat_map(|((road, plate), sightings)| {
+                sightings
+                    .iter()
+                    .map(move |sighting| (*road, plate.as_str(), *sighting))
+            })
+    }
+}
+
+/// A plate seen by a camera, ordered by timestamp first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Sighting {
+    pub(crate) timestamp: u32,
+    pub(crate) mile: u16,
+}
+
+/// The journey between two sightings, unless they are at the same time.
+fn journey(road: u16, limit: u16, s1: Sighting, s2: Sighting) -> Option<Journey> {
+    let (s1, s2) = if s1.timestamp <= s2.timestamp {
+        (s1, s2)
+    } else {
+        (s2, s1)
+    };
+    let distance = u64::from(s1.mile.abs_diff(s2.mile));
+    let time = u64::from(s2.timestamp - s1.timestamp);
+    if time == 0 {
+        return None;
+    }
+    let journey = Journey {
+        road,
+        limit,
+        mile1: s1.mile,
+        timestamp1: s1.timestamp,
+        mile2: s2.mile,
+        timestamp2: s2.timestamp,
+        speed: distance * 3600 * 100 / time,
+    };
+    Some(journey)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::SpeedTolerance;
+    use crate::protocol::IAmCamera;
+    use crate::protocol::Plate;
+    use crate::protocol::Ticket;
+    use crate::tickets::Ledger;
+    use crate::tickets::Observations;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::collections::VecDeque;
+    #[test]
+    fn record_speeding_test() {
+        let mut observations = Observations::default();
+        let camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 60,
+        };
+        let plate1 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        let plate2 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 45,
+        };
+        assert!(observations.record(camera1, plate1).is_empty());
+        let [ticket] = observations.record(camera2, plate2).try_into().unwrap();
+        assert_eq!(ticket.plate, "UN1X".to_owned());
+        assert_eq!(ticket.road, 123);
+        assert_eq!(ticket.mile1, 8);
+        assert_eq!(ticket.timestamp1, 0);
+        assert_eq!(ticket.mile2, 9);
+        assert_eq!(ticket.timestamp2, 45);
+        assert_eq!(ticket.speed, 8000);
+    }
+    #[test]
+    fn record_reversed_sightings_test() {
+        let mut observations = Observations::default();
+        let camera1 = IAmCamera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+        let camera2 = IAmCamera {
+            road: 123,
+            mile: 9,
+            limit: 60,
+        };
+        let plate1 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 45,
+        };
+        let plate2 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        assert!(observations.record(camera2, plate1).is_empty());
+        let [ticket] = observations.record(camera1, plate2).try_into().unwrap();
+        assert_eq!(ticket.mile1, 8);
+        assert_eq!(ticket.timestamp1, 0);
+        assert_eq!(ticket.mile2, 9);
+        assert_eq!(ticket.timestamp2, 45);
+    }
+    #[test]
+    fn record_between_sightings_test() {
+        let mut observations = Observations::default();
+        let sightings = [(0, 0), (20, 1200), (10, 900)];
+        let mut tickets = Vec::new();
+        for (mile, timestamp) in sightings {
+            let camera = IAmCamera {
+                road: 1,
+                mile,
+                limit: 60,
+            };
+            let plate = Plate {
+                plate: "AB12".to_owned(),
+                timestamp,
+            };
+            tickets.extend(observations.record(camera, plate));
+        }
+        // 20 miles in 20 minutes is the limit, but the last 10 of them took 5 minutes.
+        let [ticket] = tickets.try_into().unwrap();
+        assert_eq!(ticket.mile1, 10);
+        assert_eq!(ticket.timestamp1, 900);
+        assert_eq!(ticket.mile2, 20);
+        assert_eq!(ticket.timestamp2, 1200);
+        assert_eq!(ticket.speed, 12000);
+    }
+    #[test]
+    fn record_within_tolerance_test() {
+        let mut observations = Observations::default();
+        let camera1 = IAmCamera {
+            road: 1,
+            mile: 0,
+            limit: 60,
+        };
+        let camera2 = IAmCamera {
+            road: 1,
+            mile: 1,
+            limit: 60,
+        };
+        let plate1 = Plate {
+            plate: "AB12".to_owned(),
+            timestamp: 0,
+        };
+        // One mile in 60 seconds is exactly the limit.
+        let plate2 = Plate {
+            plate: "AB12".to_owned(),
+            timestamp: 60,
+        };
+        assert!(observations.record(camera1, plate1).is_empty());
+        assert!(observations.record(camera2, plate2).is_empty());
+    }
+    #[test]
+    fn record_custom_tolerance_test() {
+        let mut observations = Observations::new(Box::new(SpeedTolerance(0)));
+        let camera1 = IAmCamera {
+            road: 1,
+            mile: 0,
+            limit: 60,
+        };
+        let camera2 = IAmCamera {
+            road: 1,
+            mile: 1,
+            limit: 60,
+        };
+        let plate1 = Plate {
+            plate: "AB12".to_owned(),
+            timestamp: 0,
+        };
+        let plate2 = Plate {
+            plate: "AB12".to_owned(),
+            timestamp: 60,
+        };
+        assert!(observations.record(camera1, plate1).is_empty());
+        let [ticket] = observations.record(camera2, plate2).try_into().unwrap();
+        assert_eq!(ticket.speed, 6000);
+    }
+    #[test]
+    fn record_other_road_test() {
+        let mut observations = Observations::default();
+        let camera1 = IAmCamera {
+            road: 1,
+            mile: 8,
+            limit: 60,
+        };
+        let camera2 = IAmCamera {
+            road: 2,
+            mile: 9,
+            limit: 60,
+        };
+        let plate1 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 0,
+        };
+        let plate2 = Plate {
+            plate: "UN1X".to_owned(),
+            timestamp: 45,
+        };
+        assert!(observations.record(camera1, plate1).is_empty());
+        assert!(observations.record(camera2, plate2).is_empty());
+    }
+    #[test]
+    fn ledger_test() {
+        let mut ledger = Ledger::default();
+        let ticket1 = Ticket {
+            plate: "UN1X".to_owned(),
+            road: 123,
+            mile1: 8,
+            timestamp1: 86000,
+            mile2: 9,
+            timestamp2: 86500,
+            speed: 8000,
+        };
+        let ticket2 = Ticket {
+            timestamp1: 90000,
+            timestamp2: 90045,
+            ..ticket1.clone()
+        };
+        let ticket3 = Ticket {
+            timestamp1: 172800,
+            timestamp2: 172845,
+            ..ticket1.clone()
+        };
+        let ticket4 = Ticket {
+            plate: "AB12".to_owned(),
+            ..ticket2.clone()
+        };
+        assert!(ledger.issue(&ticket1));
+        assert!(!ledger.issue(&ticket1));
+        assert!(!ledger.issue(&ticket2));
+        assert!(ledger.issue(&ticket3));
+        assert!(ledger.issue(&ticket4));
+        ledger.revoke(&ticket1);
+        assert!(ledger.issue(&ticket2));
+        assert!(!ledger.issue(&ticket3));
+    }
+    /// Miles and distinct timestamps of the sightings of a car.
+    fn sightings() -> impl Strategy<Value = Vec<(u16, u32)>> {
+        prop::collection::btree_map(0u32..20000, 0u16..200, 2..10).prop_map(|sightings| {
+            sightings
+                .into_iter()
+                .map(|(timestamp, mile)| (mile, timestamp))
+                .collect()
+        })
+    }
+    fn record_all(sightings: &[(u16, u32)]) -> BTreeSet<(u16, u32, u16, u32, u16)> {
+        let mut observations = Observations::default();
+        let mut tickets = BTreeSet::new();
+        for (mile, timestamp) in sightings {
+            let camera = IAmCamera {
+                road: 1,
+                mile: *mile,
+                limit: 60,
+            };
+            let plate = Plate {
+                plate: "AB12".to_owned(),
+                timestamp: *timestamp,
+            };
+            for ticket in observations.record(camera, plate) {
+                let key = (
+                    ticket.mile1,
+                    ticket.timestamp1,
+                    ticket.mile2,
+                    ticket.timestamp2,
+                    ticket.speed,
+                );
+                tickets.insert(key);
+            }
+        }
+        tickets
+    }
+    /// Tickets for every pair of sightings that are next to each other at some point while recording them in
+    /// order, worked out without the store.
+    fn neighbour_tickets(sightings: &[(u16, u32)]) -> BTreeSet<(u16, u32, u16, u32, u16)> {
+        let mut recorded = BTreeMap::new();
+        let mut tickets = BTreeSet::new();
+        for (mile, timestamp) in sightings {
+            recorded.insert(*timestamp, *mile);
+            let previous = recorded.range(..*timestamp).next_back();
+            let next = recorded.range(*timestamp + 1..).next();
+            for (other_timestamp, other_mile) in previous.into_iter().chain(next) {
+                let ((mile1, timestamp1), (mile2, timestamp2)) = if other_timestamp < timestamp {
+                    ((*other_mile, *other_timestamp), (*mile, *timestamp))
+                } else {
+                    ((*mile, *timestamp), (*other_mile, *other_timestamp))
+                };
+                let speed = u64::from(mile1.abs_diff(mile2)) * 3600 * 100
+                    / u64::from(timestamp2 - timestamp1);
+                if speed >= 6050 {
+                    let speed = speed.try_into().unwrap_or(u16::MAX);
+                    tickets.insert((mile1, timestamp1, mile2, timestamp2, speed));
+                }
+            }
+        }
+        tickets
+    }
+    proptest! {
+        #[test]
+        fn record_shuffled_test(
+            (sorted, shuffled) in sightings()
+                .prop_flat_map(|sightings| (Just(sightings.clone()), Just(sightings).prop_shuffle()))
+        ) {
+            let in_order = record_all(&sorted);
+            let shuffled_tickets = record_all(&shuffled);
+            // Whatever the order, every pair of sightings that end up next to each other is checked. Out of order,
+            // so is every pair that a later sighting landed between, which are the only tickets that differ.
+            prop_assert_eq!(&in_order, &neighbour_tickets(&sorted));
+            prop_assert_eq!(&shuffled_tickets, &neighbour_tickets(&shuffled));
+            prop_assert!(in_order.is_subset(&shuffled_tickets));
+            // Each extra ticket spans a ticket given in order, since a car cannot average more than its fastest
+            // stretch.
+            for (_, timestamp1, _, timestamp2, _) in shuffled_tickets.difference(&in_order) {
+                let spanned = in_order
+                    .iter()
+                    .any(|(_, t1, _, t2, _)| timestamp1 <= t1 && t2 <= timestamp2);
+                prop_assert!(spanned);
+            }
+        }
+        #[test]
+        fn record_outwards_test(
+            (sorted, fronts) in sightings().prop_flat_map(|sightings| {
+                let len = sightings.len();
+                (Just(sightings), prop::collection::vec(any::<bool>(), len))
+            })
+        ) {
+            // Each sighting is recorded before or after all those recorded so far, so that none lands between
+            // two others, and the tickets are those given in order.
+            let mut remaining = VecDeque::from(sorted.clone());
+            let mut outwards = fronts
+                .into_iter()
+                .filter_map(|front| if front { remaining.pop_front() } else { remaining.pop_back() })
+                .collect::<Vec<(u16, u32)>>();
+            outwards.reverse();
+            prop_assert_eq!(record_all(&sorted), record_all(&outwards));
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use crate::config::TlsConfig;
+use rustls::crypto::ring;
+use rustls::pki_types::pem;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::ServerConfig;
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+/// Builds the acceptor of TLS connections from the PEM files of the certificate chain and private key.
+pub(crate) fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<CertificateDer>, pem::Error>>())
+        .map_err(pem_error)?;
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(pem_error)?;
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn pem_error(err: pem::Error) -> io::Error {
+    match err {
+        pem::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}